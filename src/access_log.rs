@@ -0,0 +1,182 @@
+//! Per-request access logs, emitted under the `spx::access` target once the response body has
+//! been sent (or abandoned).
+
+use ::{
+    futures_util::Stream,
+    hyper::{
+        body::Bytes,
+        http::{self, Method, StatusCode},
+    },
+    std::{
+        net::SocketAddr,
+        pin::Pin,
+        sync::Once,
+        task::{self, Poll},
+        time::{Duration, Instant},
+    },
+    time::OffsetDateTime,
+};
+
+const TARGET: &str = "spx::access";
+
+#[derive(Clone, Copy)]
+pub(crate) enum Format {
+    /// A single human-readable line.
+    Human,
+    /// A JSON object, for ingestion by log processors.
+    Json,
+    /// The W3C Extended Log File Format, preceded once by its `#Fields:` directive.
+    W3c,
+}
+
+const W3C_FIELDS: &str = "date time c-ip cs-method cs-uri sc-status time-taken";
+
+pub(crate) struct Entry {
+    /// The access log format, or `None` if the entry is only kept to catch slow requests.
+    format: Option<Format>,
+    slow_threshold: Option<Duration>,
+    start: Instant,
+    client: Option<SocketAddr>,
+    method: Method,
+    uri: String,
+    host: Option<String>,
+    upstream: Option<String>,
+    status: StatusCode,
+    bytes: u64,
+}
+
+impl Entry {
+    /// Starts an entry for a request, or returns `None` if access logging is disabled and there is
+    /// no slow request threshold.
+    pub(crate) fn start(
+        format: Format,
+        slow_threshold: Option<Duration>,
+        client: Option<SocketAddr>,
+        req: &http::Request<hyper::Body>,
+        host: Option<String>,
+    ) -> Option<Self> {
+        let enabled = log::log_enabled!(target: TARGET, log::Level::Info);
+        if !enabled && slow_threshold.is_none() {
+            return None;
+        }
+        Some(Self {
+            format: enabled.then_some(format),
+            slow_threshold,
+            start: Instant::now(),
+            client,
+            method: req.method().clone(),
+            uri: req
+                .uri()
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_owned(),
+            host,
+            upstream: None,
+            status: StatusCode::OK,
+            bytes: 0,
+        })
+    }
+
+    pub(crate) fn set_upstream(&mut self, upstream: &str) {
+        self.upstream = Some(upstream.to_owned());
+    }
+
+    /// Arranges for the entry to be logged once the response body has been sent.
+    pub(crate) fn finish(
+        mut self,
+        response: http::Response<hyper::Body>,
+    ) -> http::Response<hyper::Body> {
+        self.status = response.status();
+        response.map(|body| hyper::Body::wrap_stream(Counted { body, entry: self }))
+    }
+
+    fn log(&self) {
+        let duration = self.start.elapsed();
+        if self
+            .slow_threshold
+            .is_some_and(|threshold| duration > threshold)
+        {
+            log::warn!(
+                "slow request: {} {}{} took {duration:?}",
+                self.method,
+                self.upstream.as_deref().unwrap_or("-"),
+                self.uri,
+            );
+        }
+
+        let Some(format) = self.format else {
+            return;
+        };
+        let client = self.client.map(|client| client.ip().to_string());
+        match format {
+            Format::Human => log::info!(
+                target: TARGET,
+                "{} {} {} -> {} {} {}B {duration:?}",
+                client.as_deref().unwrap_or("-"),
+                self.method,
+                self.host.as_deref().unwrap_or("-"),
+                self.upstream.as_deref().unwrap_or("-"),
+                self.status.as_u16(),
+                self.bytes,
+            ),
+            Format::Json => log::info!(
+                target: TARGET,
+                "{}",
+                serde_json::json!({
+                    "client": client,
+                    "method": self.method.as_str(),
+                    "host": self.host,
+                    "upstream": self.upstream,
+                    "status": self.status.as_u16(),
+                    "bytes": self.bytes,
+                    "duration_ms": duration.as_secs_f64() * 1000.0,
+                }),
+            ),
+            Format::W3c => {
+                static FIELDS: Once = Once::new();
+                FIELDS.call_once(|| {
+                    log::info!(target: TARGET, "#Version: 1.0");
+                    log::info!(target: TARGET, "#Fields: {W3C_FIELDS}");
+                });
+                let now = OffsetDateTime::now_utc();
+                log::info!(
+                    target: TARGET,
+                    "{} {:02}:{:02}:{:02} {} {} {} {} {:.3}",
+                    now.date(),
+                    now.hour(),
+                    now.minute(),
+                    now.second(),
+                    client.as_deref().unwrap_or("-"),
+                    self.method,
+                    self.uri,
+                    self.status.as_u16(),
+                    duration.as_secs_f64(),
+                );
+            }
+        }
+    }
+}
+
+/// A response body that counts the bytes sent, logging its entry when dropped.
+struct Counted {
+    body: hyper::Body,
+    entry: Entry,
+}
+
+impl Stream for Counted {
+    type Item = hyper::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(chunk))) = &poll {
+            self.entry.bytes += chunk.len() as u64;
+        }
+        poll
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.entry.log();
+    }
+}
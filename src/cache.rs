@@ -0,0 +1,345 @@
+//! An in-memory cache of upstream responses, bounded by total size and evicting the least recently
+//! used entries first.
+
+use ::{
+    futures_util::Stream,
+    hyper::{
+        body::Bytes,
+        http::{
+            self,
+            header::{self, HeaderMap, HeaderValue},
+            Method, StatusCode,
+        },
+    },
+    std::{
+        collections::{BTreeMap, HashMap},
+        mem,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{self, Poll},
+        time::{Duration, Instant},
+    },
+};
+
+pub(crate) struct Config {
+    /// The total size of cached response bodies to keep.
+    pub(crate) max_bytes: usize,
+    /// Responses with larger bodies than this are never cached.
+    pub(crate) max_entry_bytes: usize,
+    /// Whether to say how the cache handled each request in an `X-Cache` response header.
+    pub(crate) status_header: bool,
+}
+
+pub(crate) struct Cache {
+    config: Config,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// The key of every entry, ordered by when it was last used.
+    recency: BTreeMap<u64, String>,
+    /// Incremented every time an entry is used.
+    clock: u64,
+    bytes: usize,
+}
+
+struct Entry {
+    headers: HeaderMap,
+    body: Bytes,
+    /// When the response was received from the upstream.
+    received: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+impl Cache {
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            config,
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                recency: BTreeMap::new(),
+                clock: 0,
+                bytes: 0,
+            }),
+        }
+    }
+
+    /// Returns the key a request to the given upstream host would be cached under, or `None` if
+    /// it can't be served from the cache.
+    pub(crate) fn key(req: &http::Request<hyper::Body>, upstream_host: &str) -> Option<String> {
+        let headers = req.headers();
+        if req.method() != Method::GET
+            || headers.contains_key(header::AUTHORIZATION)
+            || headers.contains_key(header::UPGRADE)
+        {
+            return None;
+        }
+        let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+        Some(format!("{} {upstream_host}{path}", req.method()))
+    }
+
+    pub(crate) fn status_header(&self) -> bool {
+        self.config.status_header
+    }
+
+    pub(crate) fn get(&self, key: &str, now: Instant) -> Option<http::Response<hyper::Body>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key)?;
+        if entry.expires <= now {
+            inner.remove(key);
+            return None;
+        }
+        let mut response = http::Response::new(hyper::Body::from(entry.body.clone()));
+        *response.headers_mut() = entry.headers.clone();
+        // The age includes any the response already had when it was received.
+        let cached_for = now.saturating_duration_since(entry.received).as_secs();
+        let age = HeaderValue::from(upstream_age(&entry.headers) + cached_for);
+        response.headers_mut().insert(header::AGE, age);
+        let last_used = entry.last_used;
+        inner.touch(last_used);
+        Some(response)
+    }
+
+    /// Arranges for the response to be cached under the given key once its body has been sent, if
+    /// it is cacheable.
+    pub(crate) fn fill(
+        self: &Arc<Self>,
+        key: String,
+        response: http::Response<hyper::Body>,
+    ) -> http::Response<hyper::Body> {
+        let Some(lifetime) = lifetime(&response) else {
+            return response;
+        };
+        let too_large = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse::<usize>().ok())
+            .is_some_and(|len| len > self.config.max_entry_bytes);
+        if too_large {
+            return response;
+        }
+
+        let (parts, body) = response.into_parts();
+        let received = Instant::now();
+        let filling = Filling {
+            body,
+            cache: self.clone(),
+            key,
+            headers: parts.headers.clone(),
+            received,
+            expires: received + lifetime,
+            buffer: Some(Vec::new()),
+        };
+        http::Response::from_parts(parts, hyper::Body::wrap_stream(filling))
+    }
+
+    fn insert(
+        &self,
+        key: String,
+        headers: HeaderMap,
+        body: Bytes,
+        received: Instant,
+        expires: Instant,
+    ) {
+        if body.len() > self.config.max_entry_bytes || body.len() > self.config.max_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&key);
+        while inner.bytes + body.len() > self.config.max_bytes {
+            let Some((_, oldest)) = inner.recency.pop_first() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+
+        inner.clock += 1;
+        let last_used = inner.clock;
+        inner.bytes += body.len();
+        inner.recency.insert(last_used, key.clone());
+        inner.entries.insert(
+            key,
+            Entry {
+                headers,
+                body,
+                received,
+                expires,
+                last_used,
+            },
+        );
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.recency.remove(&entry.last_used);
+            self.bytes -= entry.body.len();
+        }
+    }
+
+    /// Marks an entry as the most recently used.
+    fn touch(&mut self, last_used: u64) {
+        self.clock += 1;
+        let key = self.recency.remove(&last_used).unwrap();
+        self.recency.insert(self.clock, key.clone());
+        self.entries.get_mut(&key).unwrap().last_used = self.clock;
+    }
+}
+
+/// How long a response may be cached for, or `None` if it may not be cached at all.
+///
+/// Only successful responses that explicitly allow shared caching are cached. Responses that set
+/// cookies or vary on request headers are never cached, since they may differ between clients, and
+/// neither are ones with malformed lifetimes.
+fn lifetime(response: &http::Response<hyper::Body>) -> Option<Duration> {
+    let headers = response.headers();
+    if response.status() != StatusCode::OK
+        || headers.contains_key(header::SET_COOKIE)
+        || headers.contains_key(header::VARY)
+    {
+        return None;
+    }
+
+    let (mut max_age, mut s_maxage) = (None, None);
+    for value in headers.get_all(header::CACHE_CONTROL) {
+        for directive in value.to_str().ok()?.split(',') {
+            let (name, value) = directive
+                .trim()
+                .split_once('=')
+                .unwrap_or((directive.trim(), ""));
+            match &*name.to_ascii_lowercase() {
+                "no-store" | "no-cache" | "private" => return None,
+                "max-age" => max_age = Some(value.trim_matches('"').parse::<u64>().ok()?),
+                "s-maxage" => s_maxage = Some(value.trim_matches('"').parse::<u64>().ok()?),
+                _ => {}
+            }
+        }
+    }
+    // The lifetime counts from when the response was generated, which an upstream cache may have
+    // done some time ago.
+    s_maxage
+        .or(max_age)
+        .map(|secs| secs.saturating_sub(upstream_age(headers)))
+        .filter(|&secs| secs != 0)
+        .map(Duration::from_secs)
+}
+
+/// How many seconds old a response already was when it was received, according to its `Age`
+/// header.
+fn upstream_age(headers: &HeaderMap) -> u64 {
+    headers
+        .get(header::AGE)
+        .and_then(|age| age.to_str().ok()?.parse().ok())
+        .unwrap_or(0)
+}
+
+/// A response body that caches itself once it has been sent in full.
+struct Filling {
+    body: hyper::Body,
+    cache: Arc<Cache>,
+    key: String,
+    headers: HeaderMap,
+    received: Instant,
+    expires: Instant,
+    /// The body so far, or `None` if it has become too large or failed.
+    buffer: Option<Vec<u8>>,
+}
+
+impl Stream for Filling {
+    type Item = hyper::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        let this = &mut *self;
+        match &poll {
+            Poll::Ready(Some(Ok(chunk))) => {
+                let max = this.cache.config.max_entry_bytes;
+                if let Some(buffer) = &mut this.buffer {
+                    buffer.extend_from_slice(chunk);
+                    if buffer.len() > max {
+                        this.buffer = None;
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.buffer = None,
+            Poll::Ready(None) => {
+                if let Some(buffer) = this.buffer.take() {
+                    let (key, headers) = (mem::take(&mut this.key), mem::take(&mut this.headers));
+                    let body = buffer.into();
+                    this.cache
+                        .insert(key, headers, body, this.received, this.expires);
+                }
+            }
+            Poll::Pending => {}
+        }
+        poll
+    }
+}
+
+#[test]
+fn cacheable_lifetimes() {
+    let response = |cache_control: &str| {
+        http::Response::builder()
+            .header(header::CACHE_CONTROL, cache_control)
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+    let secs = |secs| Some(Duration::from_secs(secs));
+
+    assert_eq!(lifetime(&response("max-age=60")), secs(60));
+    assert_eq!(lifetime(&response("public, Max-Age=60")), secs(60));
+    assert_eq!(lifetime(&response("max-age=60, s-maxage=600")), secs(600));
+    assert_eq!(lifetime(&response("max-age=0")), None);
+    assert_eq!(lifetime(&response("max-age=60, no-store")), None);
+    assert_eq!(lifetime(&response("private, max-age=60")), None);
+    assert_eq!(lifetime(&response("public")), None);
+    assert_eq!(lifetime(&response("max-age=soon")), None);
+    assert_eq!(lifetime(&response("max-age=-1")), None);
+    assert_eq!(lifetime(&response("max-age=60, s-maxage=")), None);
+
+    let mut aged = response("max-age=60");
+    aged.headers_mut()
+        .insert(header::AGE, HeaderValue::from_static("45"));
+    assert_eq!(lifetime(&aged), secs(15));
+
+    let mut not_found = response("max-age=60");
+    *not_found.status_mut() = StatusCode::NOT_FOUND;
+    assert_eq!(lifetime(&not_found), None);
+}
+
+#[test]
+fn least_recently_used_eviction() {
+    let cache = Cache::new(Config {
+        max_bytes: 10,
+        max_entry_bytes: 5,
+        status_header: true,
+    });
+    let now = Instant::now();
+    let expires = now + Duration::from_secs(60);
+    let insert = |key: &str, body: &'static [u8]| {
+        let body = Bytes::from(body);
+        cache.insert(key.to_owned(), HeaderMap::new(), body, now, expires);
+    };
+
+    insert("a", b"aaaa");
+    insert("b", b"bbbb");
+    insert("too large", b"xxxxxx");
+    assert!(cache.get("too large", now).is_none());
+
+    // Using `a` makes `b` the one to be evicted.
+    assert!(cache.get("a", now).is_some());
+    insert("c", b"cccc");
+    assert!(cache.get("a", now).is_some());
+    assert!(cache.get("b", now).is_none());
+    assert!(cache.get("c", now).is_some());
+    assert_eq!(cache.inner.lock().unwrap().bytes, 8);
+
+    let later = now + Duration::from_secs(7);
+    assert_eq!(cache.get("c", later).unwrap().headers()[header::AGE], "7");
+
+    assert!(cache.get("a", expires).is_none());
+    assert_eq!(cache.inner.lock().unwrap().bytes, 4);
+}
@@ -0,0 +1,404 @@
+//! On-the-fly compression of responses the upstream sent uncompressed.
+
+use ::{
+    futures_util::Stream,
+    hyper::{
+        body::Bytes,
+        http::{
+            self,
+            header::{self, HeaderMap, HeaderValue},
+            Method, StatusCode,
+        },
+    },
+    std::{
+        io::{self, Read as _, Write as _},
+        mem,
+        pin::Pin,
+        task::{self, Poll},
+    },
+};
+
+pub(crate) struct Config {
+    /// Responses known to be smaller than this are sent uncompressed, since compressing them
+    /// would barely save anything.
+    pub(crate) min_bytes: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Encoding {
+    Gzip,
+    Brotli,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Brotli => "br",
+        }
+    }
+}
+
+/// Chooses the encoding to compress the response to a request with, preferring Brotli, or
+/// returns `None` if the client doesn't accept any supported encoding.
+pub(crate) fn negotiate(req: &http::Request<hyper::Body>) -> Option<Encoding> {
+    if req.method() == Method::HEAD {
+        return None;
+    }
+    let (mut gzip, mut brotli) = (false, false);
+    let accepted = req
+        .headers()
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+    for coding in accepted {
+        let mut params = coding.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .filter_map(|param| param.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case("gzip") {
+            gzip = q > 0.0;
+        } else if name.eq_ignore_ascii_case("br") {
+            brotli = q > 0.0;
+        }
+    }
+    if brotli {
+        Some(Encoding::Brotli)
+    } else if gzip {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Compresses the response with the given encoding, if it is worth compressing.
+pub(crate) fn compress(
+    response: http::Response<hyper::Body>,
+    encoding: Encoding,
+    config: &Config,
+) -> http::Response<hyper::Body> {
+    if !is_compressible(&response, config) {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let headers = &mut parts.headers;
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.name()),
+    );
+    headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    // The compressed body is no longer byte-for-byte the same as the upstream's, so its validator
+    // can only be weak.
+    if let Some(etag) = headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            headers.insert(header::ETAG, HeaderValue::from_bytes(&weak).unwrap());
+        }
+    }
+
+    let encoder = match encoding {
+        Encoding::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(
+            Vec::new(),
+            flate2::Compression::default(),
+        )),
+        Encoding::Brotli => Encoder::Brotli(Box::new(brotli::CompressorWriter::new(
+            Vec::new(),
+            BROTLI_BUFFER_SIZE,
+            BROTLI_QUALITY,
+            BROTLI_WINDOW_BITS,
+        ))),
+    };
+    let compressed = Compressed {
+        body,
+        encoder: Some(encoder),
+    };
+    http::Response::from_parts(parts, hyper::Body::wrap_stream(compressed))
+}
+
+/// Whether bodies with the given `Content-Encoding` can be decompressed.
+pub(crate) fn can_decompress(encoding: &HeaderValue) -> bool {
+    ["gzip", "x-gzip", "deflate", "br"].iter().any(|supported| {
+        encoding
+            .as_bytes()
+            .eq_ignore_ascii_case(supported.as_bytes())
+    })
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum DecompressError {
+    /// The body isn't valid for its encoding, or the encoding isn't supported.
+    Invalid,
+    /// The body decompresses to more than the limit, so it could be a decompression bomb.
+    TooLarge,
+}
+
+/// Decompresses a body with the given `Content-Encoding`, to at most `limit` bytes.
+pub(crate) fn decompress(
+    encoding: &HeaderValue,
+    body: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>, DecompressError> {
+    let limit = limit as u64;
+    let read = |reader: &mut dyn io::Read| {
+        let mut decompressed = Vec::new();
+        reader
+            .take(limit + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| DecompressError::Invalid)?;
+        if decompressed.len() as u64 > limit {
+            return Err(DecompressError::TooLarge);
+        }
+        Ok(decompressed)
+    };
+    let encoding = encoding.to_str().map_err(|_| DecompressError::Invalid)?;
+    match &*encoding.to_ascii_lowercase() {
+        "gzip" | "x-gzip" => read(&mut flate2::read::MultiGzDecoder::new(body)),
+        // `deflate` is meant to be zlib-wrapped, but some servers send raw deflate data instead.
+        "deflate" => match read(&mut flate2::read::ZlibDecoder::new(body)) {
+            Err(DecompressError::Invalid) => read(&mut flate2::read::DeflateDecoder::new(body)),
+            result => result,
+        },
+        "br" => read(&mut brotli::Decompressor::new(body, BROTLI_BUFFER_SIZE)),
+        _ => Err(DecompressError::Invalid),
+    }
+}
+
+/// A lower quality than the default, since responses are compressed as they are sent.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW_BITS: u32 = 22;
+const BROTLI_BUFFER_SIZE: usize = 4096;
+
+fn is_compressible(response: &http::Response<hyper::Body>, config: &Config) -> bool {
+    let status = response.status();
+    if !status.is_success()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::PARTIAL_CONTENT
+    {
+        return false;
+    }
+
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) || has_no_transform(headers) {
+        return false;
+    }
+
+    let too_small = headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<u64>().ok())
+        .is_some_and(|len| len < config.min_bytes);
+    if too_small {
+        return false;
+    }
+
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(is_compressible_type)
+}
+
+fn has_no_transform(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"))
+}
+
+fn is_compressible_type(content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    let Some((kind, subtype)) = essence.split_once('/') else {
+        return false;
+    };
+    match kind {
+        // Event streams must reach the client as soon as each event is sent, which compression
+        // would prevent.
+        "text" => subtype != "event-stream",
+        "application" => {
+            matches!(
+                subtype,
+                "json" | "javascript" | "xml" | "wasm" | "manifest+json"
+            ) || subtype.ends_with("+json")
+                || subtype.ends_with("+xml")
+        }
+        "image" => subtype == "svg+xml",
+        _ => false,
+    }
+}
+
+enum Encoder {
+    Gzip(flate2::write::GzEncoder<Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+}
+
+impl Encoder {
+    /// Compresses some data, returning whatever compressed output is ready so far.
+    fn write(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let output = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+            Self::Brotli(encoder) => {
+                encoder.write_all(data)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(mem::take(output).into())
+    }
+
+    fn finish(self) -> io::Result<Bytes> {
+        Ok(match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Brotli(encoder) => encoder.into_inner(),
+        }
+        .into())
+    }
+}
+
+/// A response body that is compressed as it is sent.
+struct Compressed {
+    body: hyper::Body,
+    /// Becomes `None` once the body has ended.
+    encoder: Option<Encoder>,
+}
+
+impl Stream for Compressed {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            let Some(encoder) = &mut this.encoder else {
+                return Poll::Ready(None);
+            };
+            let output = match Pin::new(&mut this.body).poll_next(cx) {
+                Poll::Ready(Some(Ok(chunk))) => encoder.write(&chunk),
+                Poll::Ready(Some(Err(e))) => Err(io::Error::other(e)),
+                Poll::Ready(None) => this.encoder.take().unwrap().finish(),
+                Poll::Pending => return Poll::Pending,
+            };
+            match output {
+                // Most chunks are absorbed by the encoder without producing any output yet.
+                Ok(output) if output.is_empty() => {}
+                output => return Poll::Ready(Some(output)),
+            }
+        }
+    }
+}
+
+#[test]
+fn decompressing() {
+    let text = b"<a href=\"https://www.rust-lang.org/\">Rust</a>".repeat(10);
+
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(&text).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let encoding = HeaderValue::from_static("gzip");
+    assert_eq!(decompress(&encoding, &gzip, 1000).unwrap(), text);
+    assert_eq!(
+        decompress(&encoding, &gzip, text.len() - 1),
+        Err(DecompressError::TooLarge)
+    );
+    assert_eq!(
+        decompress(&encoding, b"not gzip", 1000),
+        Err(DecompressError::Invalid)
+    );
+
+    let mut deflate =
+        flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+    deflate.write_all(&text).unwrap();
+    let deflate = deflate.finish().unwrap();
+    let encoding = HeaderValue::from_static("deflate");
+    assert_eq!(decompress(&encoding, &deflate, 1000).unwrap(), text);
+    assert_eq!(
+        decompress(&encoding, &deflate, 10),
+        Err(DecompressError::TooLarge)
+    );
+
+    let mut brotli = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+    brotli.write_all(&text).unwrap();
+    let brotli = brotli.into_inner();
+    let encoding = HeaderValue::from_static("br");
+    assert_eq!(decompress(&encoding, &brotli, 1000).unwrap(), text);
+}
+
+#[test]
+fn negotiation() {
+    let request = |accept_encoding| {
+        http::Request::builder()
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+    assert_eq!(
+        negotiate(&request("gzip, deflate, br")),
+        Some(Encoding::Brotli)
+    );
+    assert_eq!(negotiate(&request("GZIP")), Some(Encoding::Gzip));
+    assert_eq!(
+        negotiate(&request("br;q=0, gzip;q=0.5")),
+        Some(Encoding::Gzip)
+    );
+    assert_eq!(negotiate(&request("identity")), None);
+    assert_eq!(negotiate(&request("gzip;q=0")), None);
+}
+
+#[tokio::test]
+async fn compressing() {
+    use std::io::Read as _;
+
+    let config = Config { min_bytes: 16 };
+    let response = |content_type, body: &'static str| {
+        http::Response::builder()
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, body.len())
+            .header(header::ETAG, "\"v1\"")
+            .body(hyper::Body::from(body))
+            .unwrap()
+    };
+    let text = "hello world, hello world, hello world";
+
+    let compressed = compress(response("text/plain", text), Encoding::Gzip, &config);
+    let headers = compressed.headers();
+    assert_eq!(headers[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(headers[header::VARY], "accept-encoding");
+    assert_eq!(headers[header::ETAG], "W/\"v1\"");
+    assert!(!headers.contains_key(header::CONTENT_LENGTH));
+    let body = hyper::body::to_bytes(compressed.into_body()).await.unwrap();
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(&*body)
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, text);
+
+    let compressed = compress(
+        response("application/json; charset=utf-8", text),
+        Encoding::Brotli,
+        &config,
+    );
+    assert_eq!(compressed.headers()[header::CONTENT_ENCODING], "br");
+    let body = hyper::body::to_bytes(compressed.into_body()).await.unwrap();
+    let mut decompressed = String::new();
+    brotli::Decompressor::new(&*body, 4096)
+        .read_to_string(&mut decompressed)
+        .unwrap();
+    assert_eq!(decompressed, text);
+
+    let small = compress(response("text/plain", "hi"), Encoding::Gzip, &config);
+    assert!(!small.headers().contains_key(header::CONTENT_ENCODING));
+    let image = compress(response("image/png", text), Encoding::Gzip, &config);
+    assert!(!image.headers().contains_key(header::CONTENT_ENCODING));
+}
@@ -1,73 +1,861 @@
 use {
-    crate::{proxy, server},
+    crate::{access_log, cache, compression, load_shed, proxy, rate_limit, server},
     ::{
-        anyhow::Context,
+        anyhow::{bail, ensure, Context},
+        hyper::{
+            header::{HeaderName, HeaderValue},
+            StatusCode, Uri,
+        },
         regex::Regex,
+        schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema},
         serde::{
             de::{self, Deserializer},
             Deserialize,
         },
         std::{
+            collections::HashMap,
             fmt::{self, Formatter},
+            fs,
             net::{IpAddr, SocketAddr},
-            path::PathBuf,
+            path::{Path, PathBuf},
             time::Duration,
         },
     },
 };
 
+/// Generates a JSON schema describing the config file format.
+pub(crate) fn schema() -> String {
+    let schema = schemars::schema_for!(Config);
+    serde_json::to_string_pretty(&schema).unwrap()
+}
+
 pub(crate) fn read(file: &str) -> anyhow::Result<server::Config> {
     let config = toml::from_str::<Config>(file).context("config file is invalid")?;
+    let forwarding = read_forwarding(&config.proxy);
+
+    let deny_user_agents = read_deny_user_agents(
+        config.proxy.deny_user_agents,
+        config.proxy.deny_user_agents_file,
+    )?;
+
+    let hsts = config.tls.hsts.as_ref().map(read_hsts);
+    let resolver = read_resolver(
+        config.proxy.resolver,
+        config.proxy.dns_cache,
+        config.proxy.ip_version,
+    );
 
     // TODO: avoid this
     Ok(server::Config {
+        bind_address: config.bind_address,
         http_port: config.http_port,
         https_port: config.https_port,
-        tls: server::TlsConfig {
-            refresh: Duration::from_secs(config.tls.refresh_mins * 60),
-            chain: config.tls.chain,
-            key: config.tls.key,
+        unix_socket: config.unix_socket.map(UnixSocket::into_config),
+        reject_unknown_protocol: config.reject_unknown_protocol_ms.map(Duration::from_millis),
+        http2_max_concurrent_streams: config.http2_max_concurrent_streams,
+        max_requests_per_connection: config.max_requests_per_connection,
+        max_connections: config.max_connections.map(|max| server::ConnectionLimit {
+            max,
+            close_when_full: matches!(config.at_max_connections, AtMaxConnections::Close),
+            require_fd_limit: matches!(config.low_fd_limit, LowFdLimit::Error),
+        }),
+        bind_retry: server::Retry {
+            attempts: config.bind_retry.attempts,
+            delay: Duration::from_millis(config.bind_retry.delay_ms),
         },
+        shutdown_timeout: Duration::from_secs(config.shutdown_timeout_secs),
+        tls: read_tls(config.tls)?,
         proxy: proxy::Config {
             domain: config.proxy.domain,
-            resolver: match config.proxy.resolver {
-                Resolver::System => proxy::resolver::Config::System,
-                Resolver::TrustDns(config) => proxy::resolver::Config::TrustDns(config),
+            resolver,
+            deny_user_agents,
+            upstream_tcp_user_timeout: config
+                .proxy
+                .upstream_tcp_user_timeout_ms
+                .map(Duration::from_millis),
+            upstream_tcp_keepalive: config.proxy.tcp.keepalive_secs.map(Duration::from_secs),
+            upstream_http2_prior_knowledge: config.proxy.upstream_http2_prior_knowledge,
+            preserve_header_case: config.proxy.preserve_header_case,
+            no_sni_hosts: config.proxy.no_sni_hosts,
+            rewrite_html: config.proxy.rewrite_html,
+            max_decompressed_html: config.proxy.max_decompressed_html_bytes,
+            csp: match config.proxy.csp {
+                Csp::Keep => proxy::Csp::Keep,
+                Csp::Strip => proxy::Csp::Strip,
+                Csp::Rewrite => proxy::Csp::Rewrite,
             },
-            deny_user_agents: config.proxy.deny_user_agents,
+            allowed_domains: config.proxy.allowed_domains,
+            allow_private_ips: config.proxy.allow_private_ips,
+            own_ips: config.proxy.own_ips,
+            max_subdomain_labels: config.proxy.max_subdomain_labels,
+            health_path: config.proxy.health_path,
+            metrics_path: config.metrics.enabled.then_some(config.metrics.path),
+            rewrite_referer: config.proxy.rewrite_referer,
+            normalize_path: config.proxy.normalize_path,
+            forwarding,
+            load_shedding: config
+                .load_shedding
+                .map(LoadShedding::into_config)
+                .transpose()?,
+            compression: config.proxy.compression.into_config(),
+            hsts,
+            rate_limit: config.rate_limit.map(RateLimit::into_config).transpose()?,
+            cache: config.cache.map(Cache::into_config),
+            upstream_timeout: config
+                .proxy
+                .upstream_timeout_ms
+                .filter(|&ms| ms != 0)
+                .map(Duration::from_millis),
+            upstream_time_header: config.proxy.upstream_time_header,
+            max_response_header_bytes: config.proxy.max_response_header_bytes,
+            access_log_format: config.log.format.into_config(),
+            slow_request_threshold: config
+                .log
+                .slow_request_threshold_ms
+                .map(Duration::from_millis),
+            merge_headers: config
+                .proxy
+                .merge_headers
+                .iter()
+                .map(|name| header_name(name))
+                .collect::<anyhow::Result<_>>()?,
+            variants: read_all_variants(config.proxy.variants)?,
+            responses: config.responses.into_config()?,
+        },
+        startup_probes: config
+            .proxy
+            .startup_probes
+            .into_iter()
+            .map(Probe::into_config)
+            .collect::<anyhow::Result<_>>()?,
+    })
+}
+
+fn read_deny_user_agents(
+    regex: Option<Regex>,
+    file: Option<PathBuf>,
+) -> anyhow::Result<Option<Regex>> {
+    Ok(match (regex, file) {
+        (Some(_), Some(_)) => {
+            bail!("only one of `deny_user_agents` and `deny_user_agents_file` may be set")
+        }
+        (Some(regex), None) => Some(regex),
+        (None, Some(path)) => Some(read_regex_file(&path)?),
+        (None, None) => None,
+    })
+}
+
+fn read_resolver(
+    resolver: Resolver,
+    dns_cache: DnsCache,
+    ip_version: IpVersion,
+) -> proxy::resolver::Config {
+    // Addresses are filtered after caching, so that the cache holds everything that was resolved.
+    let resolver = dns_cache.wrap(resolver.into_config());
+    let ip_version = match ip_version {
+        IpVersion::Both => return resolver,
+        IpVersion::V4Only => proxy::resolver::IpVersion::V4Only,
+        IpVersion::V6Only => proxy::resolver::IpVersion::V6Only,
+        IpVersion::PreferV6 => proxy::resolver::IpVersion::PreferV6,
+    };
+    proxy::resolver::Config::IpVersion(Box::new(resolver), ip_version)
+}
+
+fn read_tls(tls: Tls) -> anyhow::Result<server::TlsConfig> {
+    if let Some(length) = tls.max_fragment_length {
+        if !(32..=16389).contains(&length) {
+            bail!("`tls.max_fragment_length` must be between 32 and 16389");
+        }
+    }
+
+    Ok(server::TlsConfig {
+        refresh: Duration::from_secs(tls.refresh_mins * 60),
+        max_concurrent_handshakes: tls.max_concurrent_handshakes,
+        require_alpn: tls.require_alpn,
+        max_fragment_length: tls.max_fragment_length,
+        log_sni: tls.log_sni,
+        handshake_timeout: Duration::from_millis(tls.handshake_timeout_ms),
+        load_retry: server::Retry {
+            attempts: tls.load_retry.attempts,
+            delay: Duration::from_secs(tls.load_retry.delay_secs),
         },
+        cert_expiry_warning: Duration::from_secs(tls.cert_expiry_warn_days * 24 * 60 * 60),
+        certificates: std::iter::once(Certificate {
+            chain: tls.chain,
+            key: tls.key,
+        })
+        .chain(tls.additional)
+        .map(|certificate| server::CertificateConfig {
+            chain: certificate.chain,
+            key: certificate.key,
+        })
+        .collect(),
     })
 }
 
-#[derive(Deserialize)]
+fn read_hsts(hsts: &Hsts) -> HeaderValue {
+    let mut value = format!("max-age={}", hsts.max_age_secs);
+    if hsts.include_subdomains {
+        value.push_str("; includeSubDomains");
+    }
+    if hsts.preload {
+        value.push_str("; preload");
+    }
+    HeaderValue::try_from(value).unwrap()
+}
+
+/// Reads the variants of every site, keyed by the site in the same form as request hosts: lowercase
+/// and without a trailing dot.
+fn read_forwarding(proxy: &Proxy) -> proxy::Forwarding {
+    proxy::Forwarding {
+        trusted: proxy.trust_forwarded,
+        replace_chain: matches!(proxy.forwarded_for, ForwardedFor::Replace),
+        max_chain: proxy.max_forwarded_for,
+    }
+}
+
+fn read_all_variants(
+    sites: HashMap<String, Variants>,
+) -> anyhow::Result<HashMap<String, proxy::Variants>> {
+    let mut all = HashMap::new();
+    for (site, variants) in sites {
+        let variants = read_variants(&site, variants)?;
+        let key = site.trim_end_matches('.').to_ascii_lowercase();
+        if all.insert(key, variants).is_some() {
+            bail!("variants of {site} are given more than once");
+        }
+    }
+    Ok(all)
+}
+
+fn read_variants(site: &str, variants: Variants) -> anyhow::Result<proxy::Variants> {
+    if variants.upstreams.is_empty() {
+        bail!("variants of {site} must list at least one upstream");
+    }
+    let key = match variants.key {
+        VariantKey::Cookie(name) => proxy::VariantKey::Cookie(name),
+        VariantKey::Header(name) => proxy::VariantKey::Header(header_name(&name)?),
+    };
+    Ok(proxy::Variants {
+        upstreams: variants.upstreams,
+        key,
+    })
+}
+
+fn header_name(name: &str) -> anyhow::Result<HeaderName> {
+    name.parse()
+        .with_context(|| format!("invalid header name {name}"))
+}
+
+#[derive(Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Config {
+    #[serde(default = "default_bind_address")]
+    bind_address: IpAddr,
     http_port: u16,
     https_port: u16,
+    unix_socket: Option<UnixSocket>,
+    reject_unknown_protocol_ms: Option<u64>,
+    http2_max_concurrent_streams: Option<u32>,
+    max_requests_per_connection: Option<u64>,
+    max_connections: Option<usize>,
+    #[serde(default)]
+    at_max_connections: AtMaxConnections,
+    #[serde(default)]
+    low_fd_limit: LowFdLimit,
+    #[serde(default)]
+    bind_retry: BindRetry,
+    #[serde(default = "default_shutdown_timeout_secs")]
+    shutdown_timeout_secs: u64,
     tls: Tls,
     proxy: Proxy,
+    #[serde(default)]
+    metrics: Metrics,
+    #[serde(default)]
+    log: Log,
+    load_shedding: Option<LoadShedding>,
+    rate_limit: Option<RateLimit>,
+    cache: Option<Cache>,
+    #[serde(default)]
+    responses: Responses,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum AtMaxConnections {
+    #[default]
+    Wait,
+    Close,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum ForwardedFor {
+    #[default]
+    Append,
+    Replace,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum LowFdLimit {
+    #[default]
+    Warn,
+    Error,
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    30
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct LoadShedding {
+    #[serde(default = "default_min_concurrency")]
+    min_concurrency: usize,
+    #[serde(default = "default_max_concurrency")]
+    max_concurrency: usize,
+    #[serde(default = "default_max_lag_ms")]
+    max_lag_ms: u64,
+}
+
+impl LoadShedding {
+    fn into_config(self) -> anyhow::Result<load_shed::Config> {
+        ensure!(
+            0 < self.min_concurrency && self.min_concurrency <= self.max_concurrency,
+            "`load_shedding.min_concurrency` must be positive and at most `max_concurrency`",
+        );
+        Ok(load_shed::Config {
+            min_concurrency: self.min_concurrency,
+            max_concurrency: self.max_concurrency,
+            max_lag: Duration::from_millis(self.max_lag_ms),
+        })
+    }
+}
+
+fn default_min_concurrency() -> usize {
+    16
+}
+
+fn default_max_concurrency() -> usize {
+    1024
+}
+
+fn default_max_lag_ms() -> u64 {
+    50
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct UnixSocket {
+    path: PathBuf,
+    mode: Option<u32>,
+    #[serde(default)]
+    tls: bool,
+}
+
+impl UnixSocket {
+    fn into_config(self) -> server::UnixSocket {
+        server::UnixSocket {
+            path: self.path,
+            mode: self.mode,
+            tls: self.tls,
+        }
+    }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct BindRetry {
+    attempts: u32,
+    delay_ms: u64,
+}
+
+impl Default for BindRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            delay_ms: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Tls {
     refresh_mins: u64,
+    max_concurrent_handshakes: Option<usize>,
+    require_alpn: Option<String>,
+    max_fragment_length: Option<usize>,
+    #[serde(default)]
+    log_sni: bool,
+    #[serde(default)]
+    load_retry: LoadRetry,
+    #[serde(default = "default_handshake_timeout_ms")]
+    handshake_timeout_ms: u64,
+    hsts: Option<Hsts>,
+    #[serde(default = "default_cert_expiry_warn_days")]
+    cert_expiry_warn_days: u64,
+    chain: PathBuf,
+    key: PathBuf,
+    #[serde(default)]
+    additional: Vec<Certificate>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct LoadRetry {
+    attempts: u32,
+    delay_secs: u64,
+}
+
+impl Default for LoadRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            delay_secs: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Hsts {
+    max_age_secs: u64,
+    #[serde(default)]
+    include_subdomains: bool,
+    #[serde(default)]
+    preload: bool,
+}
+
+fn default_bind_address() -> IpAddr {
+    IpAddr::from([0, 0, 0, 0])
+}
+
+fn default_handshake_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_cert_expiry_warn_days() -> u64 {
+    14
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Certificate {
     chain: PathBuf,
     key: PathBuf,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, JsonSchema)]
 #[serde(deny_unknown_fields)]
 struct Proxy {
     domain: String,
     resolver: Resolver,
-    #[serde(with = "serde_regex")]
-    deny_user_agents: Regex,
+    #[serde(default)]
+    dns_cache: DnsCache,
+    #[serde(default)]
+    ip_version: IpVersion,
+    #[serde(default, with = "serde_regex")]
+    #[schemars(with = "Option<String>")]
+    deny_user_agents: Option<Regex>,
+    deny_user_agents_file: Option<PathBuf>,
+    upstream_tcp_user_timeout_ms: Option<u64>,
+    #[serde(default)]
+    tcp: Tcp,
+    upstream_timeout_ms: Option<u64>,
+    #[serde(default)]
+    upstream_time_header: bool,
+    #[serde(default = "default_max_response_header_bytes")]
+    max_response_header_bytes: usize,
+    #[serde(default)]
+    upstream_http2_prior_knowledge: bool,
+    #[serde(default)]
+    preserve_header_case: bool,
+    #[serde(default)]
+    no_sni_hosts: Vec<String>,
+    #[serde(default)]
+    startup_probes: Vec<Probe>,
+    #[serde(default)]
+    variants: HashMap<String, Variants>,
+    #[serde(default)]
+    rewrite_html: bool,
+    #[serde(default = "default_max_decompressed_html_bytes")]
+    max_decompressed_html_bytes: usize,
+    #[serde(default)]
+    csp: Csp,
+    #[serde(default)]
+    allowed_domains: Vec<String>,
+    #[serde(default)]
+    merge_headers: Vec<String>,
+    #[serde(default)]
+    allow_private_ips: bool,
+    #[serde(default)]
+    own_ips: Vec<IpAddr>,
+    max_subdomain_labels: Option<usize>,
+    #[serde(default = "default_health_path")]
+    health_path: String,
+    #[serde(default)]
+    rewrite_referer: bool,
+    #[serde(default)]
+    normalize_path: bool,
+    #[serde(default)]
+    trust_forwarded: bool,
+    #[serde(default)]
+    forwarded_for: ForwardedFor,
+    max_forwarded_for: Option<usize>,
+    #[serde(default)]
+    compression: Compression,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Tcp {
+    keepalive_secs: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Compression {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_compression_min_bytes")]
+    min_bytes: u64,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_bytes: default_compression_min_bytes(),
+        }
+    }
+}
+
+impl Compression {
+    fn into_config(self) -> Option<compression::Config> {
+        self.enabled.then_some(compression::Config {
+            min_bytes: self.min_bytes,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Default, Deserialize, JsonSchema)]
+enum IpVersion {
+    #[default]
+    #[serde(rename = "both")]
+    Both,
+    #[serde(rename = "v4only")]
+    V4Only,
+    #[serde(rename = "v6only")]
+    V6Only,
+    #[serde(rename = "prefer_v6")]
+    PreferV6,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct DnsCache {
+    #[serde(default = "default_dns_cache_capacity")]
+    capacity: usize,
+    #[serde(default = "default_dns_cache_ttl_secs")]
+    ttl_secs: u64,
+    #[serde(default = "default_dns_cache_negative_ttl_secs")]
+    negative_ttl_secs: u64,
+}
+
+impl Default for DnsCache {
+    fn default() -> Self {
+        Self {
+            capacity: default_dns_cache_capacity(),
+            ttl_secs: default_dns_cache_ttl_secs(),
+            negative_ttl_secs: default_dns_cache_negative_ttl_secs(),
+        }
+    }
+}
+
+impl DnsCache {
+    /// Puts the cache in front of the given resolver, unless it is disabled.
+    fn wrap(self, resolver: proxy::resolver::Config) -> proxy::resolver::Config {
+        if self.capacity == 0 {
+            return resolver;
+        }
+        let cache = proxy::resolver::CacheConfig {
+            capacity: self.capacity,
+            ttl: Duration::from_secs(self.ttl_secs),
+            negative_ttl: Duration::from_secs(self.negative_ttl_secs),
+        };
+        proxy::resolver::Config::Cached(Box::new(resolver), cache)
+    }
+}
+
+fn default_dns_cache_capacity() -> usize {
+    1024
+}
+
+fn default_dns_cache_ttl_secs() -> u64 {
+    60
+}
+
+fn default_dns_cache_negative_ttl_secs() -> u64 {
+    5
+}
+
+fn default_compression_min_bytes() -> u64 {
+    1024
+}
+
+fn default_max_decompressed_html_bytes() -> usize {
+    100 * 1024 * 1024
+}
+
+fn default_max_response_header_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_health_path() -> String {
+    "/healthz".to_owned()
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Metrics {
+    #[serde(default)]
+    enabled: bool,
+    #[serde(default = "default_metrics_path")]
+    path: String,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_metrics_path(),
+        }
+    }
+}
+
+fn default_metrics_path() -> String {
+    "/metrics".to_owned()
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum Csp {
+    #[default]
+    Keep,
+    Strip,
+    Rewrite,
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Log {
+    #[serde(default)]
+    format: LogFormat,
+    slow_request_threshold_ms: Option<u64>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Cache {
+    max_bytes: usize,
+    #[serde(default = "default_cache_max_entry_bytes")]
+    max_entry_bytes: usize,
+    #[serde(default = "default_cache_status_header")]
+    status_header: bool,
+}
+
+impl Cache {
+    fn into_config(self) -> cache::Config {
+        cache::Config {
+            max_bytes: self.max_bytes,
+            max_entry_bytes: self.max_entry_bytes,
+            status_header: self.status_header,
+        }
+    }
+}
+
+fn default_cache_max_entry_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_cache_status_header() -> bool {
+    true
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct RateLimit {
+    requests_per_second: f64,
+    burst: u32,
+}
+
+impl RateLimit {
+    fn into_config(self) -> anyhow::Result<rate_limit::Config> {
+        ensure!(
+            self.requests_per_second > 0.0 && self.requests_per_second.is_finite(),
+            "`rate_limit.requests_per_second` must be positive",
+        );
+        Ok(rate_limit::Config {
+            requests_per_second: self.requests_per_second,
+            burst: self.burst,
+        })
+    }
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum LogFormat {
+    #[default]
+    Human,
+    Json,
+    W3c,
+}
+
+impl LogFormat {
+    fn into_config(self) -> access_log::Format {
+        match self {
+            Self::Human => access_log::Format::Human,
+            Self::Json => access_log::Format::Json,
+            Self::W3c => access_log::Format::W3c,
+        }
+    }
+}
+
+#[derive(Default, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Responses {
+    #[serde(rename = "403")]
+    forbidden: Option<CustomResponse>,
+    #[serde(rename = "429")]
+    too_many_requests: Option<CustomResponse>,
+    #[serde(rename = "503")]
+    service_unavailable: Option<CustomResponse>,
+}
+
+impl Responses {
+    fn into_config(self) -> anyhow::Result<HashMap<StatusCode, proxy::CustomResponse>> {
+        [
+            (StatusCode::FORBIDDEN, self.forbidden),
+            (StatusCode::TOO_MANY_REQUESTS, self.too_many_requests),
+            (StatusCode::SERVICE_UNAVAILABLE, self.service_unavailable),
+        ]
+        .into_iter()
+        .filter_map(|(status, response)| Some((status, response?)))
+        .map(|(status, response)| {
+            let content_type = HeaderValue::try_from(response.content_type).with_context(|| {
+                format!("invalid content type for {} responses", status.as_u16())
+            })?;
+            let body = response.body.into();
+            Ok((status, proxy::CustomResponse { content_type, body }))
+        })
+        .collect()
+    }
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct CustomResponse {
+    content_type: String,
+    body: String,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Variants {
+    upstreams: Vec<String>,
+    key: VariantKey,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+enum VariantKey {
+    Cookie(String),
+    Header(String),
+}
+
+/// Reads a file containing one regex per line into a single regex matching any of them. Blank
+/// lines and lines starting with `#` are ignored.
+fn read_regex_file(path: &Path) -> anyhow::Result<Regex> {
+    let file = fs::read_to_string(path)
+        .with_context(|| format!("failed to read regex file {}", path.display()))?;
+    let alternation = file
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| format!("(?:{line})"))
+        .collect::<Vec<_>>();
+    // An empty regex would match everything.
+    ensure!(
+        !alternation.is_empty(),
+        "regex file {} contains no patterns",
+        path.display()
+    );
+    let alternation = alternation.join("|");
+    Regex::new(&alternation).with_context(|| format!("invalid regex in {}", path.display()))
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+struct Probe {
+    url: String,
+    #[serde(default)]
+    required: bool,
+}
+
+impl Probe {
+    fn into_config(self) -> anyhow::Result<server::Probe> {
+        Ok(server::Probe {
+            url: self
+                .url
+                .parse()
+                .with_context(|| format!("invalid startup probe URL {}", self.url))?,
+            required: self.required,
+        })
+    }
 }
 
 pub(crate) enum Resolver {
     System,
+    ResolvConf,
     TrustDns(trust_dns_resolver::config::ResolverConfig),
+    Chain(Vec<Resolver>),
+    Hosts {
+        hosts: HashMap<String, Vec<IpAddr>>,
+        fallback: Box<Resolver>,
+    },
+    DnsOverHttps {
+        server_name: String,
+        port: u16,
+        ips: Option<Vec<IpAddr>>,
+    },
+}
+
+impl Resolver {
+    fn into_config(self) -> proxy::resolver::Config {
+        match self {
+            Self::System => proxy::resolver::Config::System,
+            Self::ResolvConf => proxy::resolver::Config::ResolvConf,
+            Self::TrustDns(config) => proxy::resolver::Config::TrustDns(config),
+            Self::Chain(resolvers) => proxy::resolver::Config::Chain(
+                resolvers.into_iter().map(Self::into_config).collect(),
+            ),
+            Self::Hosts { hosts, fallback } => proxy::resolver::Config::Hosts {
+                hosts,
+                fallback: Box::new(fallback.into_config()),
+            },
+            Self::DnsOverHttps {
+                server_name,
+                port,
+                ips,
+            } => proxy::resolver::Config::DnsOverHttps {
+                server_name,
+                port,
+                ips,
+            },
+        }
+    }
 }
 
 macro_rules! with_trust_dns_resolvers {
@@ -95,12 +883,13 @@ impl<'de> Deserialize<'de> for Resolver {
                     ($($name:ident: $_desc:literal,)*) => {
                         match v {
                             "system" => Resolver::System,
+                            "resolv-conf" => Resolver::ResolvConf,
                             $(stringify!($name) => {
                                 Resolver::TrustDns(trust_dns_resolver::config::ResolverConfig::$name())
                             })*
                             _ => return Err(de::Error::unknown_variant(
                                 v,
-                                &[$(stringify!($name),)*],
+                                &["system", "resolv-conf", $(stringify!($name),)*],
                             )),
                         }
                     };
@@ -117,18 +906,168 @@ impl<'de> Deserialize<'de> for Resolver {
                         protocol: trust_dns_resolver::config::Protocol::default(),
                         tls_dns_name: None,
                         trust_nx_responses: true,
+                        tls_config: None,
                         bind_addr: None,
                     });
                 }
 
                 Ok(Resolver::TrustDns(config))
             }
+
+            fn visit_map<A: de::MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+                const FIELDS: &[&str] =
+                    &["chain", "hosts", "fallback", "doh", "ips", "server_name"];
+                let (mut chain, mut hosts, mut fallback) = (None, None, None);
+                let (mut doh, mut ips, mut server_name) = (None::<String>, None, None);
+                while let Some(key) = map.next_key::<String>()? {
+                    match &*key {
+                        "chain" if chain.is_none() => chain = Some(map.next_value()?),
+                        "hosts" if hosts.is_none() => hosts = Some(map.next_value()?),
+                        "fallback" if fallback.is_none() => fallback = Some(map.next_value()?),
+                        "doh" if doh.is_none() => doh = Some(map.next_value()?),
+                        "ips" if ips.is_none() => ips = Some(map.next_value()?),
+                        "server_name" if server_name.is_none() => {
+                            server_name = Some(map.next_value()?);
+                        }
+                        "chain" | "hosts" | "fallback" | "doh" | "ips" | "server_name" => {
+                            return Err(de::Error::custom(format_args!("duplicate field `{key}`")));
+                        }
+                        _ => return Err(de::Error::unknown_field(&key, FIELDS)),
+                    }
+                }
+                match (chain, hosts, doh) {
+                    (Some(chain), None, None)
+                        if fallback.is_none() && ips.is_none() && server_name.is_none() =>
+                    {
+                        Ok(Resolver::Chain(chain))
+                    }
+                    (None, Some(hosts), None) if ips.is_none() && server_name.is_none() => {
+                        Ok(Resolver::Hosts {
+                            hosts,
+                            fallback: Box::new(fallback.unwrap_or(Resolver::System)),
+                        })
+                    }
+                    (None, None, Some(url)) if fallback.is_none() => {
+                        dns_over_https(&url, ips, server_name).map_err(de::Error::custom)
+                    }
+                    (None, None, None) => Err(de::Error::missing_field("chain")),
+                    _ => Err(de::Error::custom(
+                        "expected only one of `chain`, `hosts` (with an optional `fallback`) or \
+                        `doh` (with optional `ips` and `server_name`)",
+                    )),
+                }
+            }
         }
 
         deserializer.deserialize_str(Visitor)
     }
 }
 
+/// Configures DNS-over-HTTPS with the server at the given URL. An IP address in the URL can't be
+/// used to verify the server's certificate, so it needs a separate `server_name`.
+fn dns_over_https(
+    url: &str,
+    ips: Option<Vec<IpAddr>>,
+    server_name: Option<String>,
+) -> Result<Resolver, String> {
+    let uri = url
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid DNS-over-HTTPS URL {url}: {e}"))?;
+    if uri.scheme_str() != Some("https") {
+        return Err(format!("DNS-over-HTTPS URL {url} must use https"));
+    }
+    if !matches!(uri.path(), "" | "/" | "/dns-query") || uri.query().is_some() {
+        return Err(format!(
+            "DNS-over-HTTPS URL {url} must use the standard `/dns-query` path"
+        ));
+    }
+    let host = uri
+        .host()
+        .ok_or_else(|| format!("DNS-over-HTTPS URL {url} has no host"))?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let port = uri.port_u16().unwrap_or(443);
+
+    let (server_name, ips) = match (host.parse::<IpAddr>(), server_name) {
+        (Ok(ip), Some(server_name)) => (server_name, Some(ips.unwrap_or_else(|| vec![ip]))),
+        (Ok(_), None) => {
+            return Err(format!(
+                "DNS-over-HTTPS URL {url} has an IP address as its host, so `server_name` must \
+                be set to the name in the server's certificate"
+            ));
+        }
+        (Err(_), None) => (host.to_owned(), ips),
+        (Err(_), Some(_)) => {
+            return Err(format!(
+                "`server_name` may only be set when DNS-over-HTTPS URL {url} has an IP address \
+                as its host"
+            ));
+        }
+    };
+    if matches!(&ips, Some(ips) if ips.is_empty()) {
+        return Err(format!("no addresses for DNS-over-HTTPS server {host}"));
+    }
+
+    Ok(Resolver::DnsOverHttps {
+        server_name,
+        port,
+        ips,
+    })
+}
+
+impl JsonSchema for Resolver {
+    fn schema_name() -> String {
+        "Resolver".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        macro_rules! gen_names {
+            ($($name:ident: $_desc:literal,)*) => {
+                ["system", "resolv-conf", $(stringify!($name),)*]
+            };
+        }
+        let schema = serde_json::json!({
+            "oneOf": [
+                {
+                    "type": "string",
+                    "enum": with_trust_dns_resolvers!(gen_names),
+                },
+                {
+                    "type": "array",
+                    "items": gen.subschema_for::<IpAddr>(),
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "chain": gen.subschema_for::<Vec<Resolver>>(),
+                    },
+                    "required": ["chain"],
+                    "additionalProperties": false,
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "hosts": gen.subschema_for::<HashMap<String, Vec<IpAddr>>>(),
+                        "fallback": gen.subschema_for::<Resolver>(),
+                    },
+                    "required": ["hosts"],
+                    "additionalProperties": false,
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "doh": { "type": "string", "format": "uri" },
+                        "ips": gen.subschema_for::<Vec<IpAddr>>(),
+                        "server_name": { "type": "string" },
+                    },
+                    "required": ["doh"],
+                    "additionalProperties": false,
+                },
+            ],
+        });
+        serde_json::from_value(schema).unwrap()
+    }
+}
+
 pub(crate) fn initial_config() -> &'static str {
     INITIAL_CONFIG
 }
@@ -138,23 +1077,112 @@ macro_rules! gen_initial_config {
     ($($resolver_name:ident: $resolver_desc:literal,)*) => {
 const INITIAL_CONFIG: &str = concat!(r#"# SPX configuration file
 
+# The IP address to listen on, such as "127.0.0.1" to only accept local connections or "::" for
+# all IPv6 (and usually also IPv4) addresses.
+bind_address = "0.0.0.0"
+
 # The port to serve plain HTTP on.
 http_port = 80
 
 # The port to serve HTTPS on.
 https_port = 443
 
+# If set, serve on a Unix domain socket at `path` instead of on the above ports, for example behind
+# a local reverse proxy. A socket left over from a previous run is replaced, and `mode` optionally
+# sets the socket's permissions. With `tls = true` HTTPS is served on the socket, and otherwise
+# plain HTTP. Connections over the socket have no client address, so they aren't rate limited and
+# don't get one added to `X-Forwarded-For`; set `trust_forwarded` to keep the forwarding headers
+# sent by the reverse proxy.
+#
+# unix_socket = { path = "/run/spx/spx.sock", mode = 0o660, tls = false }
+
+# If set, connections that don't start sending an HTTP request within this many milliseconds, or
+# that start by sending something other than HTTP, are closed. For HTTPS connections, this is
+# measured from after the TLS handshake.
+#
+# reject_unknown_protocol_ms = 5000
+
+# If set, the maximum number of concurrent streams a single HTTP/2 client connection may open.
+#
+# http2_max_concurrent_streams = 100
+
+# If set, client connections are closed after serving this many requests, which makes long-lived
+# clients reconnect and so get rebalanced between servers behind a load balancer.
+#
+# max_requests_per_connection = 1000
+
+# If set, the maximum number of client connections (HTTP and HTTPS together) to have open at once.
+# Once reached, `at_max_connections` decides what happens to new connections: "wait" stops
+# accepting them until an open connection finishes, and "close" closes them immediately.
+#
+# At startup, SPX checks that it may open enough file descriptors for `max_connections` client
+# connections and their upstream connections. If not, `low_fd_limit` decides whether it logs a
+# warning ("warn") or refuses to start ("error"). This check only happens on Unix.
+#
+# max_connections = 10000
+# at_max_connections = "wait"
+# low_fd_limit = "warn"
+
+# How many times to try binding to the ports above, and how long to wait between attempts. Retrying
+# helps when restarting, if the previous instance hasn't released the ports yet.
+bind_retry = { attempts = 1, delay_ms = 500 }
+
+# On SIGTERM or Ctrl-C, SPX stops accepting connections and waits up to this many seconds for
+# in-flight requests to finish before exiting.
+shutdown_timeout_secs = 30
+
 [tls]
 
 # How often to reload the TLS certificates in minutes.
 refresh_mins = 720
 
+# If set, the maximum number of TLS handshakes to perform at once. Further connections wait up to
+# a second for a handshake to finish before being dropped.
+#
+# max_concurrent_handshakes = 256
+
+# If set, the application protocol (ALPN) that HTTPS clients must negotiate, either "h2" or
+# "http/1.1". Requests over connections that negotiated anything else are rejected.
+#
+# require_alpn = "h2"
+
+# If set, the maximum size in bytes of TLS records sent to clients, between 32 and 16389. Smaller
+# records reduce latency for small responses on slow networks, at the cost of throughput.
+#
+# max_fragment_length = 4096
+
+# Whether to log the server name (SNI) requested by each HTTPS client at debug level.
+log_sni = false
+
+# How many times to try loading the certificates at startup, and how many seconds to wait between
+# attempts. Retrying helps if the certificates are provisioned by another process that may not
+# have written them yet.
+load_retry = { attempts = 1, delay_secs = 3 }
+
+# How long in milliseconds clients have to complete the TLS handshake before being disconnected.
+handshake_timeout_ms = 10000
+
+# If set, responses over HTTPS include a `Strict-Transport-Security` header with this max age,
+# replacing any sent by the upstream, so that browsers only use HTTPS in future.
+#
+# hsts = { max_age_secs = 31536000, include_subdomains = true, preload = false }
+
+# Log a warning when a certificate is loaded that expires within this many days.
+cert_expiry_warn_days = 14
+
 # The TLS certificate to use when serving HTTPS
 chain = "/path/to/your/cert/fullchain.pem"
 
 # The associated private key of the above TLS certificate
 key = "/path/to/your/cert/privkey.pem"
 
+# Additional certificates for the same domain, for example to serve both an ECDSA and an RSA
+# certificate. ECDSA certificates are preferred for clients that support them.
+#
+# [[tls.additional]]
+# chain = "/path/to/your/rsa-cert/fullchain.pem"
+# key = "/path/to/your/rsa-cert/privkey.pem"
+
 [proxy]
 
 # The domain name of your server. Proxy URLs will look like "www.rust-lang.org.example.com".
@@ -163,13 +1191,39 @@ domain = "example.com"
 # The DNS resolver to use.
 #
 # Possible values:
-# - "system": Use the system default resolver."#,
+# - "system": Use the system default resolver.
+# - "resolv-conf": Use the nameservers and options in `/etc/resolv.conf`, but resolve asynchronously."#,
 $(concat!("\n# - \"", stringify!($resolver_name), "\": Use ", $resolver_desc, "."),)* r#"
 # - An array of IP addresses to use as DNS servers
+# - `{ chain = [...] }`: Try each of the listed resolvers in order until one succeeds, for example
+#   `{ chain = ["cloudflare", "system"] }`.
+# - `{ hosts = { ... }, fallback = ... }`: Resolve the listed hosts to fixed addresses, like
+#   `/etc/hosts`, and use the fallback resolver (by default "system") for everything else, for
+#   example `{ hosts = { "www.example.org" = ["203.0.113.7"] }, fallback = "cloudflare" }`.
+#   Private addresses listed here are still rejected unless `allow_private_ips` is set.
+# - `{ doh = "https://.../dns-query", ips = [...] }`: Use a DNS-over-HTTPS server, for example
+#   `{ doh = "https://cloudflare-dns.com/dns-query", ips = ["1.1.1.1", "1.0.0.1"] }`. The server's
+#   addresses are given by `ips`, or looked up with the system resolver at startup if omitted. If
+#   the URL has an IP address instead of a name, `server_name` must be set to the name in the
+#   server's certificate, for example
+#   `{ doh = "https://1.1.1.1/dns-query", server_name = "one.one.one.one" }`.
 resolver = "system"
 
+# Caching of DNS lookups. Addresses are kept for as long as the DNS records allow, or `ttl_secs`
+# when the resolver doesn't say (as with "system"). Names that don't exist are remembered for
+# `negative_ttl_secs`. Setting `capacity`, the maximum number of hosts kept, to 0 disables caching.
+dns_cache = { capacity = 1024, ttl_secs = 60, negative_ttl_secs = 5 }
+
+# Which IP versions to connect to upstreams over: "both", "v4only", "v6only" or "prefer_v6". With
+# "both", addresses are tried in the order the resolver returns them; with "prefer_v6", IPv6
+# addresses are tried first.
+ip_version = "both"
+
 # A regex that can be used to ban certain user agents.
 #
+# Alternatively, `deny_user_agents_file` can be set to the path of a file containing one regex per
+# line, any of which bans a user agent. Blank lines and lines starting with `#` are ignored.
+#
 # This default list comes from https://stackoverflow.com/a/24820722
 deny_user_agents = """(?x)
     google|bing|yandex|msnbot
@@ -180,6 +1234,207 @@ deny_user_agents = """(?x)
     |Teleport|VoidEYE|Collector|WebAuto|WebCopier|WebFetch|WebGo|WebLeacher|WebReaper|WebSauger|eXtractor|Quester|WebStripper|WebZIP|Wget|Widow|Zeus
     |Twengabot|htmlparser|libwww|Python|perl|urllib|scan|Curl|email|PycURL|Pyth|PyQ|WebCollector|WebCopy|webcraw
 """
+
+# If set, the maximum time in milliseconds that data sent to an upstream may remain
+# unacknowledged before the connection is dropped (`TCP_USER_TIMEOUT`). Only supported on Linux.
+#
+# upstream_tcp_user_timeout_ms = 30000
+
+# Socket options for connections to upstreams. If `keepalive_secs` is set, TCP keepalive probes are
+# sent after a connection has been idle for that long. `TCP_NODELAY` is always set, on client
+# connections too.
+#
+# tcp = { keepalive_secs = 60 }
+
+# If set and not zero, the maximum time in milliseconds to wait for an upstream to connect and
+# respond with headers, after which the client gets 504 Gateway Timeout. Response bodies may take
+# longer. By default there is no limit.
+#
+# upstream_timeout_ms = 30000
+
+# Whether to add an `X-Upstream-Time` header to proxied responses, giving the number of
+# milliseconds the upstream took to respond with headers. Off by default, since it exposes how the
+# upstream performs.
+upstream_time_header = false
+
+# The largest total size in bytes of the headers of an upstream response. Responses with larger
+# headers get a 502 Bad Gateway response instead, since clients and other proxies in front of SPX
+# may not accept them.
+max_response_header_bytes = 262144
+
+# Whether to speak HTTP/2 to upstreams without negotiating it first (prior knowledge). Only enable
+# this if every upstream supports HTTP/2, such as internal gRPC services.
+upstream_http2_prior_knowledge = false
+
+# Whether to keep the original casing of header names over HTTP/1.1, instead of lowercasing them.
+# This is only needed for clients or upstreams that incorrectly treat header names as
+# case-sensitive.
+preserve_header_case = false
+
+# Upstream hosts to connect to without sending SNI, for example because they are pinned to an IP
+# address that would reject or log the server name.
+no_sni_hosts = []
+
+# URLs to request through the proxy's own resolver and connector at startup, to catch DNS or
+# network problems early. If a probe marked as required fails, SPX refuses to start; other
+# failures are only logged.
+startup_probes = [
+    # { url = "https://www.rust-lang.org", required = true },
+]
+
+# Whether to rewrite absolute links in HTML pages to go through the proxy. Pages larger than 8 MiB
+# are passed through unchanged.
+rewrite_html = true
+
+# Pages compressed with gzip, deflate or Brotli are decompressed to rewrite them. Pages that
+# decompress to more than this many bytes could be decompression bombs, so they get a 502 Bad
+# Gateway response instead.
+max_decompressed_html_bytes = 104857600
+
+# What to do with `Content-Security-Policy` and `Content-Security-Policy-Report-Only` headers from
+# upstreams. Policies that only permit the upstream's own domain otherwise break pages once they are
+# served through the proxy.
+#
+# Possible values:
+# - "keep": Pass them through unchanged.
+# - "strip": Remove them, disabling the protection they provide.
+# - "rewrite": Rewrite the hosts they list to their proxied forms.
+csp = "keep"
+
+# If non-empty, only upstream hosts equal to or under one of these domains are proxied, and other
+# requests are rejected with 403 Forbidden. This prevents SPX from being used as an open proxy.
+allowed_domains = [
+    # "rust-lang.org",
+    # "docs.rs",
+]
+
+# Request headers whose repeated occurrences are joined into a single comma-separated value before
+# being forwarded, for upstreams that mishandle duplicate headers. By default headers are forwarded
+# exactly as received.
+merge_headers = [
+    # "accept-encoding",
+]
+
+# Whether to allow connecting to upstreams at private, loopback or link-local addresses. This is
+# off by default so that the proxy can't be used to reach internal services or cloud metadata
+# endpoints; only enable it when running SPX inside a trusted network.
+allow_private_ips = false
+
+# The public IP addresses of this server. Requests for upstreams that resolve to any of these are
+# rejected with 421 Misdirected Request, since they would make the proxy loop back to itself.
+# Requests for the domain above and its subdomains are always rejected.
+own_ips = [
+    # "203.0.113.1",
+]
+
+# If set, the maximum number of labels in an upstream host. For example, "www.rust-lang.org" has
+# three. Requests for deeper hosts are rejected with 400 Bad Request.
+#
+# max_subdomain_labels = 8
+
+# The path on the bare domain above that responds with 200 OK without contacting any upstream, for
+# use by load balancer health checks.
+health_path = "/healthz"
+
+# Whether to rewrite the `Referer` header of requests from the proxied form of a URL back to the
+# upstream one, so upstreams see referers from their own site.
+rewrite_referer = false
+
+# Whether to normalize request paths before sending them upstream, collapsing repeated slashes and
+# resolving `.` and `..` segments (never above the root). This protects upstreams that could be
+# confused by paths like `//admin/../public`. Off by default, so paths are passed through exactly.
+normalize_path = false
+
+# Whether to trust `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and `Forwarded`
+# headers sent by clients, appending to them instead of replacing them. Only enable this if SPX is
+# behind another proxy that sets them, since otherwise clients can spoof them.
+trust_forwarded = false
+
+# When `trust_forwarded` is set, whether to add the client's address to the end of the
+# `X-Forwarded-For` chain sent by the previous proxy ("append"), or to replace the chain with just
+# that address ("replace").
+forwarded_for = "append"
+
+# If set, the most addresses to keep in `X-Forwarded-For`. The earliest ones are dropped first, so
+# that long or malicious chains don't bloat requests.
+#
+# max_forwarded_for = 10
+
+# Whether to compress responses with gzip or Brotli when the upstream sent them uncompressed, for
+# clients that accept it. Only text-like content types are compressed, and not responses known to
+# be smaller than `min_bytes`.
+compression = { enabled = false, min_bytes = 1024 }
+
+# Alternative upstreams for a site, for A/B testing. Requests to the site are spread between the
+# listed upstreams based on the value of a cookie or header, so each client consistently sees the
+# same variant. Clients without the cookie or header get the first upstream.
+#
+# [proxy.variants."www.example.org"]
+# upstreams = ["www-a.example.org", "www-b.example.org"]
+# key = { cookie = "session" }
+
+[metrics]
+
+# Whether to serve Prometheus metrics on the bare domain, at the path below.
+enabled = false
+path = "/metrics"
+
+[log]
+
+# The format of access logs, which are logged at info level under the `spx::access` target (for
+# example, run with `RUST_LOG=spx::access=info`). Either "human" for one readable line per request,
+# "json" for one JSON object per request, or "w3c" for the W3C Extended Log File Format, with the
+# fields date, time, c-ip, cs-method, cs-uri, sc-status and time-taken (in seconds).
+format = "human"
+
+# If set, requests that take longer than this many milliseconds, until their response body has been
+# sent, are logged at warn level with their upstream host, path and duration. This happens
+# whether or not access logs are enabled.
+#
+# slow_request_threshold_ms = 5000
+
+# If this section is present, requests are rejected with 503 Service Unavailable once too many are
+# waiting for upstream responses. The limit moves between `min_concurrency` and `max_concurrency`:
+# it is lowered while the server is overloaded, detected by timers firing more than `max_lag_ms`
+# late, and raised again once it has recovered. The current limit and the number of rejected
+# requests are exposed as metrics.
+#
+# [load_shedding]
+# min_concurrency = 16
+# max_concurrency = 1024
+# max_lag_ms = 50
+
+# Limits on how often each client IP address may make requests, as a token bucket: clients may make
+# `burst` requests at once, and regain the ability to make one more `requests_per_second` times a
+# second. Requests over the limit get a 429 Too Many Requests response. Health checks and metrics
+# are not limited.
+#
+# [rate_limit]
+# requests_per_second = 10.0
+# burst = 50
+
+# An in-memory cache of upstream responses. Only successful responses to `GET` requests whose
+# `Cache-Control` header gives a `max-age` or `s-maxage` (and doesn't forbid shared caching) are
+# cached, and never ones that set cookies or have a `Vary` header.
+#
+# [cache]
+# The total size of cached response bodies in bytes, beyond which the least recently used are
+# evicted.
+# max_bytes = 67108864
+# Responses with bodies larger than this many bytes are not cached.
+# max_entry_bytes = 1048576
+# Whether responses say how the cache handled them in an `X-Cache` header: `HIT` if they came from
+# the cache, `MISS` if they didn't, and `BYPASS` if the request couldn't be served from the cache
+# at all (such as one that isn't a `GET` or has an `Authorization` header).
+# status_header = true
+
+# Bodies for the responses SPX sends when it rejects a request by policy, instead of the default
+# short plain text ones: 403 Forbidden for denied user agents and hosts outside `allowed_domains`,
+# 429 Too Many Requests for rate-limited clients, and 503 Service Unavailable for load shedding.
+# This is useful for returning machine-readable errors to API clients.
+#
+# [responses]
+# 429 = { content_type = "application/json", body = '{"error":"rate_limited"}' }
 "#);
     };
 }
@@ -189,3 +1444,179 @@ use gen_initial_config;
 fn initial_config_is_valid() {
     toml::from_str::<Config>(INITIAL_CONFIG).unwrap();
 }
+
+#[test]
+fn handshake_timeout() {
+    let config = read(INITIAL_CONFIG).unwrap();
+    assert_eq!(config.tls.handshake_timeout, Duration::from_secs(10));
+
+    let file = INITIAL_CONFIG.replace(
+        "handshake_timeout_ms = 10000",
+        "handshake_timeout_ms = 2500",
+    );
+    let config = read(&file).unwrap();
+    assert_eq!(config.tls.handshake_timeout, Duration::from_millis(2500));
+}
+
+#[test]
+fn regex_file() {
+    let path = std::env::temp_dir().join(format!("spx-test-{}-regexes", std::process::id()));
+    let read = |contents: &str| {
+        fs::write(&path, contents).unwrap();
+        read_regex_file(&path)
+    };
+
+    let regex = read("# bots\nBadBot\n\n  crawler/\\d+  \n").unwrap();
+    assert!(regex.is_match("Mozilla/5.0 BadBot"));
+    assert!(regex.is_match("crawler/2"));
+    assert!(!regex.is_match("Mozilla/5.0"));
+
+    assert!(read("").is_err());
+    assert!(read("# nothing yet\n\n").is_err());
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn variants_are_case_insensitive() {
+    let with_variants = |sites: &[&str]| {
+        let mut tables = String::from("\n");
+        for site in sites {
+            tables.push_str("[proxy.variants.\"");
+            tables.push_str(site);
+            tables.push_str("\"]\nupstreams = [\"www-a.example.org\"]\n");
+            tables.push_str("key = { cookie = \"session\" }\n");
+        }
+        tables.push_str("[metrics]\n");
+        INITIAL_CONFIG.replace("\n[metrics]\n", &tables)
+    };
+
+    let config = read(&with_variants(&["WWW.Example.org."])).unwrap();
+    let sites = config.proxy.variants.keys().collect::<Vec<_>>();
+    assert_eq!(sites, ["www.example.org"]);
+
+    assert!(read(&with_variants(&["www.example.org", "WWW.example.org"])).is_err());
+}
+
+#[test]
+fn custom_responses() {
+    let file = format!(
+        "{INITIAL_CONFIG}\n[responses]\n\
+        429 = {{ content_type = \"application/json\", body = '{{\"error\":1}}' }}\n"
+    );
+    let config = read(&file).unwrap();
+    let response = &config.proxy.responses[&StatusCode::TOO_MANY_REQUESTS];
+    assert_eq!(response.content_type, "application/json");
+    assert_eq!(response.body, r#"{"error":1}"#);
+    assert_eq!(config.proxy.responses.len(), 1);
+
+    let file =
+        format!("{INITIAL_CONFIG}\n[responses]\n404 = {{ content_type = \"\", body = \"\" }}\n");
+    assert!(read(&file).is_err());
+}
+
+#[test]
+fn schema_describes_resolvers() {
+    let schema: serde_json::Value = serde_json::from_str(&schema()).unwrap();
+    let names = &schema["definitions"]["Resolver"]["oneOf"][0]["enum"];
+    assert_eq!(
+        *names,
+        serde_json::json!(["system", "resolv-conf", "google", "cloudflare", "quad9"])
+    );
+}
+
+#[test]
+fn resolver_chain() {
+    #[derive(Deserialize)]
+    struct Test {
+        resolver: Resolver,
+    }
+    let test: Test =
+        toml::from_str(r#"resolver = { chain = ["cloudflare", ["1.1.1.1"], "system"] }"#).unwrap();
+    let Resolver::Chain(chain) = test.resolver else {
+        panic!("resolver is not a chain");
+    };
+    assert!(matches!(
+        &*chain,
+        [
+            Resolver::TrustDns(_),
+            Resolver::TrustDns(_),
+            Resolver::System
+        ]
+    ));
+}
+
+#[test]
+fn resolver_hosts() {
+    #[derive(Deserialize)]
+    struct Test {
+        resolver: Resolver,
+    }
+    let test: Test =
+        toml::from_str(r#"resolver = { hosts = { "www.example.org" = ["203.0.113.7"] } }"#)
+            .unwrap();
+    let Resolver::Hosts { hosts, fallback } = test.resolver else {
+        panic!("resolver is not a hosts table");
+    };
+    assert_eq!(
+        hosts["www.example.org"],
+        ["203.0.113.7".parse::<IpAddr>().unwrap()]
+    );
+    assert!(matches!(*fallback, Resolver::System));
+
+    let combined = r#"resolver = { chain = ["system"], hosts = {} }"#;
+    assert!(toml::from_str::<Test>(combined).is_err());
+}
+
+#[test]
+fn resolver_doh() {
+    #[derive(Deserialize)]
+    struct Test {
+        resolver: Resolver,
+    }
+    let parse = |resolver: &str| {
+        let Resolver::DnsOverHttps {
+            server_name,
+            port,
+            ips,
+        } = toml::from_str::<Test>(resolver).unwrap().resolver
+        else {
+            panic!("resolver is not DNS-over-HTTPS");
+        };
+        (server_name, port, ips)
+    };
+    let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+
+    // Without `ips`, the server isn't looked up until the resolver is created.
+    assert_eq!(
+        parse(r#"resolver = { doh = "https://cloudflare-dns.com/dns-query" }"#),
+        ("cloudflare-dns.com".to_owned(), 443, None)
+    );
+    assert_eq!(
+        parse(r#"resolver = { doh = "https://cloudflare-dns.com:8443", ips = ["1.1.1.1"] }"#),
+        (
+            "cloudflare-dns.com".to_owned(),
+            8443,
+            Some(vec![ip("1.1.1.1")])
+        )
+    );
+    assert_eq!(
+        parse(
+            r#"resolver = { doh = "https://[2606:4700::1111]", server_name = "one.one.one.one" }"#
+        ),
+        (
+            "one.one.one.one".to_owned(),
+            443,
+            Some(vec![ip("2606:4700::1111")])
+        )
+    );
+
+    for invalid in [
+        r#"resolver = { doh = "http://1.1.1.1/dns-query", server_name = "one.one.one.one" }"#,
+        r#"resolver = { doh = "https://1.1.1.1/resolve", server_name = "one.one.one.one" }"#,
+        r#"resolver = { doh = "https://1.1.1.1/dns-query" }"#,
+        r#"resolver = { doh = "https://cloudflare-dns.com/dns-query", server_name = "a.example" }"#,
+        r#"resolver = { doh = "https://cloudflare-dns.com/dns-query", ips = [] }"#,
+    ] {
+        assert!(toml::from_str::<Test>(invalid).is_err(), "{invalid}");
+    }
+}
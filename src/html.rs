@@ -0,0 +1,45 @@
+use ::{regex::bytes::Regex, std::sync::OnceLock};
+
+/// Rewrites the absolute URLs in `href`, `src` and `action` attributes of an HTML document,
+/// leaving the rest of the document untouched.
+///
+/// `rewrite` is given each URL and returns its replacement, or `None` to keep it as-is.
+pub(crate) fn rewrite_links(html: &[u8], rewrite: impl Fn(&str) -> Option<String>) -> Vec<u8> {
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    let attribute = ATTRIBUTE.get_or_init(|| {
+        Regex::new(r#"(?i)\b(?:href|src|action)\s*=\s*(?:"([^"]*)"|'([^']*)')"#).unwrap()
+    });
+
+    let mut rewritten = Vec::with_capacity(html.len());
+    let mut last = 0;
+
+    for captures in attribute.captures_iter(html) {
+        let url = captures.get(1).or_else(|| captures.get(2)).unwrap();
+        let Some(replacement) = std::str::from_utf8(url.as_bytes())
+            .ok()
+            .and_then(|url| rewrite(url.trim()))
+        else {
+            continue;
+        };
+        rewritten.extend_from_slice(&html[last..url.start()]);
+        rewritten.extend_from_slice(replacement.as_bytes());
+        last = url.end();
+    }
+
+    rewritten.extend_from_slice(&html[last..]);
+    rewritten
+}
+
+#[test]
+fn rewrites_links() {
+    let html = br#"<a href="https://a.org/x">a</a> <IMG SRC = 'http://b.org/y.png'>
+<form action="/relative"><a href=unquoted>"#;
+    let rewritten = rewrite_links(html, |url| {
+        url.starts_with("http").then(|| format!("[{url}]"))
+    });
+    assert_eq!(
+        rewritten,
+        br#"<a href="[https://a.org/x]">a</a> <IMG SRC = '[http://b.org/y.png]'>
+<form action="/relative"><a href=unquoted>"#
+    );
+}
@@ -0,0 +1,131 @@
+//! Adaptive load shedding: requests are rejected once too many are in flight, with the limit
+//! lowered while the event loop is lagging behind and raised again once it has caught up.
+
+use {
+    crate::metrics,
+    ::{
+        std::{
+            sync::{
+                atomic::{AtomicUsize, Ordering::Relaxed},
+                Arc, Weak,
+            },
+            time::Duration,
+        },
+        tokio::time::{self, Instant},
+    },
+};
+
+pub(crate) struct Config {
+    pub(crate) min_concurrency: usize,
+    pub(crate) max_concurrency: usize,
+    /// How far behind schedule timers may fire before the event loop is considered overloaded.
+    pub(crate) max_lag: Duration,
+}
+
+/// How often the event loop lag is measured and the limit adjusted.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(100);
+
+pub(crate) struct LoadShedder {
+    config: Config,
+    limit: AtomicUsize,
+    in_flight: AtomicUsize,
+}
+
+impl LoadShedder {
+    /// Creates a load shedder, starting at the maximum limit. It keeps adjusting its limit in the
+    /// background for as long as it exists.
+    pub(crate) fn new(config: Config) -> Arc<Self> {
+        let shedder = Arc::new(Self {
+            limit: AtomicUsize::new(config.max_concurrency),
+            in_flight: AtomicUsize::new(0),
+            config,
+        });
+        metrics::record_concurrency_limit(shedder.config.max_concurrency);
+        tokio::spawn(sample_lag(Arc::downgrade(&shedder)));
+        shedder
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped, or returns `None` if
+    /// the request should be shed.
+    pub(crate) fn admit(self: &Arc<Self>) -> Option<InFlight> {
+        let in_flight = self.in_flight.fetch_add(1, Relaxed);
+        if in_flight >= self.limit.load(Relaxed) {
+            self.in_flight.fetch_sub(1, Relaxed);
+            metrics::record_shed_request();
+            return None;
+        }
+        Some(InFlight(self.clone()))
+    }
+
+    /// Lowers the limit multiplicatively while the event loop is lagging, and raises it
+    /// additively otherwise.
+    fn adjust(&self, lag: Duration) {
+        let Config {
+            min_concurrency: min,
+            max_concurrency: max,
+            max_lag,
+        } = self.config;
+        let limit = self.limit.load(Relaxed);
+        let limit = if lag > max_lag {
+            log::debug!("event loop lagging by {lag:?}, lowering concurrency limit");
+            (limit * 3 / 4).max(min)
+        } else {
+            (limit + (limit / 20).max(1)).min(max)
+        };
+        self.limit.store(limit, Relaxed);
+        metrics::record_concurrency_limit(limit);
+    }
+}
+
+/// Measures how late timers fire, adjusting the limit after each measurement.
+async fn sample_lag(shedder: Weak<LoadShedder>) {
+    loop {
+        let deadline = Instant::now() + SAMPLE_INTERVAL;
+        time::sleep_until(deadline).await;
+        let lag = Instant::now().saturating_duration_since(deadline);
+        let Some(shedder) = shedder.upgrade() else {
+            break;
+        };
+        shedder.adjust(lag);
+    }
+}
+
+pub(crate) struct InFlight(Arc<LoadShedder>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Relaxed);
+    }
+}
+
+#[test]
+fn shedding() {
+    // Constructed directly so that only the test adjusts the limit.
+    let shedder = Arc::new(LoadShedder {
+        config: Config {
+            min_concurrency: 1,
+            max_concurrency: 4,
+            max_lag: Duration::from_millis(50),
+        },
+        limit: AtomicUsize::new(4),
+        in_flight: AtomicUsize::new(0),
+    });
+
+    let admitted = (0..4).map(|_| shedder.admit().unwrap()).collect::<Vec<_>>();
+    assert!(shedder.admit().is_none());
+    drop(admitted);
+
+    // Lag lowers the limit down to the minimum, and it recovers once the lag goes away.
+    for expected in [3, 2, 1, 1] {
+        shedder.adjust(Duration::from_millis(100));
+        assert_eq!(shedder.limit.load(Relaxed), expected);
+    }
+    let first = shedder.admit().unwrap();
+    assert!(shedder.admit().is_none());
+    drop(first);
+
+    for expected in [2, 3, 4, 4] {
+        shedder.adjust(Duration::ZERO);
+        assert_eq!(shedder.limit.load(Relaxed), expected);
+    }
+}
@@ -1,5 +1,9 @@
 #![warn(clippy::pedantic)]
-#![allow(clippy::single_component_path_imports, clippy::similar_names)]
+#![allow(
+    clippy::single_component_path_imports,
+    clippy::similar_names,
+    clippy::struct_excessive_bools
+)]
 
 use ::{
     anyhow::Context as _,
@@ -11,8 +15,15 @@ use ::{
     },
 };
 
+mod access_log;
+mod cache;
+mod compression;
 mod config;
+mod html;
+mod load_shed;
+mod metrics;
 mod proxy;
+mod rate_limit;
 mod server;
 
 fn main() -> anyhow::Result<()> {
@@ -41,12 +52,19 @@ enum Args {
         #[clap(long, default_value = config_path!())]
         config: PathBuf,
     },
+
+    /// Print a JSON schema describing the config file format.
+    Schema,
 }
 
 fn run_cli() -> anyhow::Result<()> {
     match Args::parse() {
         Args::Init => init(),
         Args::Serve { config } => serve(&config),
+        Args::Schema => {
+            println!("{}", config::schema());
+            Ok(())
+        }
     }
 }
 
@@ -68,6 +86,6 @@ fn init() -> anyhow::Result<()> {
 
 fn serve(config: &Path) -> anyhow::Result<()> {
     let config = fs::read_to_string(config).context("failed to open config file")?;
-    server::run(config::read(&*config)?)?;
+    server::run(config::read(&config)?)?;
     Ok(())
 }
@@ -0,0 +1,332 @@
+//! Process-wide counters, exposed in the Prometheus text format.
+//!
+//! Everything recorded per request is a relaxed atomic so that recording a metric costs no more
+//! than an uncontended atomic add.
+
+use ::{
+    hyper::http::StatusCode,
+    std::{
+        collections::BTreeMap,
+        fmt::Write as _,
+        sync::{
+            atomic::{AtomicU64, Ordering::Relaxed},
+            Mutex,
+        },
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::runtime::Handle,
+};
+
+static REQUESTS: AtomicU64 = AtomicU64::new(0);
+static RESPONSES: [AtomicU64; 5] = [const { AtomicU64::new(0) }; 5];
+static UPSTREAM_CONNECT_FAILURES: AtomicU64 = AtomicU64::new(0);
+static DNS_FAILURES: AtomicU64 = AtomicU64::new(0);
+static PROXIED_BYTES: AtomicU64 = AtomicU64::new(0);
+static UPSTREAM_LATENCY: Histogram = Histogram::new();
+static SHED_REQUESTS: AtomicU64 = AtomicU64::new(0);
+static CONCURRENCY_LIMIT: AtomicU64 = AtomicU64::new(0);
+static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static RUNTIME_THREADS: AtomicU64 = AtomicU64::new(0);
+/// When each loaded certificate chain expires, as a Unix timestamp, by path.
+static CERTIFICATE_EXPIRY: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+
+pub(crate) fn record_request() {
+    REQUESTS.fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_response(status: StatusCode) {
+    let class = usize::from(status.as_u16() / 100).clamp(1, 5) - 1;
+    RESPONSES[class].fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_upstream_connect_failure() {
+    UPSTREAM_CONNECT_FAILURES.fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_dns_failure() {
+    DNS_FAILURES.fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_proxied_bytes(bytes: usize) {
+    PROXIED_BYTES.fetch_add(bytes as u64, Relaxed);
+}
+
+pub(crate) fn record_upstream_latency(latency: Duration) {
+    UPSTREAM_LATENCY.observe(latency);
+}
+
+pub(crate) fn record_shed_request() {
+    SHED_REQUESTS.fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_concurrency_limit(limit: usize) {
+    CONCURRENCY_LIMIT.store(limit as u64, Relaxed);
+}
+
+/// Counts a client connection as open until the returned guard is dropped.
+pub(crate) fn record_connection() -> OpenConnection {
+    OPEN_CONNECTIONS.fetch_add(1, Relaxed);
+    OpenConnection(())
+}
+
+pub(crate) struct OpenConnection(());
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Relaxed);
+    }
+}
+
+pub(crate) fn record_certificate_expiry(chain: String, not_after: i64) {
+    CERTIFICATE_EXPIRY.lock().unwrap().insert(chain, not_after);
+}
+
+/// Counts a thread started by the Tokio runtime, either a worker or one in the blocking pool.
+pub(crate) fn record_runtime_thread_start() {
+    RUNTIME_THREADS.fetch_add(1, Relaxed);
+}
+
+pub(crate) fn record_runtime_thread_stop() {
+    RUNTIME_THREADS.fetch_sub(1, Relaxed);
+}
+
+/// Renders all metrics in the Prometheus text exposition format.
+pub(crate) fn render() -> String {
+    let mut out = String::new();
+
+    counter(
+        &mut out,
+        "spx_requests_total",
+        "Requests received from clients.",
+        REQUESTS.load(Relaxed),
+    );
+
+    out.push_str("# HELP spx_responses_total Responses sent to clients, by status class.\n");
+    out.push_str("# TYPE spx_responses_total counter\n");
+    for (i, count) in RESPONSES.iter().enumerate() {
+        let class = i + 1;
+        let count = count.load(Relaxed);
+        writeln!(out, "spx_responses_total{{class=\"{class}xx\"}} {count}").unwrap();
+    }
+
+    counter(
+        &mut out,
+        "spx_upstream_connect_failures_total",
+        "Failed attempts to connect to an upstream.",
+        UPSTREAM_CONNECT_FAILURES.load(Relaxed),
+    );
+    counter(
+        &mut out,
+        "spx_dns_failures_total",
+        "Failed DNS lookups of upstream hosts.",
+        DNS_FAILURES.load(Relaxed),
+    );
+    counter(
+        &mut out,
+        "spx_proxied_bytes_total",
+        "Bytes of upstream response bodies sent to clients.",
+        PROXIED_BYTES.load(Relaxed),
+    );
+    counter(
+        &mut out,
+        "spx_shed_requests_total",
+        "Requests rejected by load shedding.",
+        SHED_REQUESTS.load(Relaxed),
+    );
+
+    gauge(
+        &mut out,
+        "spx_concurrency_limit",
+        "The current load shedding limit on in-flight requests.",
+        CONCURRENCY_LIMIT.load(Relaxed),
+    );
+    gauge(
+        &mut out,
+        "spx_open_connections",
+        "Client connections currently open.",
+        OPEN_CONNECTIONS.load(Relaxed),
+    );
+
+    UPSTREAM_LATENCY.render(
+        &mut out,
+        "spx_upstream_latency_seconds",
+        "Time until upstreams responded with headers.",
+    );
+
+    render_certificate_expiry(&mut out);
+
+    if let Ok(runtime) = Handle::try_current() {
+        render_runtime(&mut out, &runtime);
+    }
+
+    out
+}
+
+fn render_certificate_expiry(out: &mut String) {
+    let expiry = CERTIFICATE_EXPIRY.lock().unwrap();
+    if expiry.is_empty() {
+        return;
+    }
+    let name = "spx_tls_certificate_expiry_seconds";
+    writeln!(
+        out,
+        "# HELP {name} Time until each TLS certificate expires."
+    )
+    .unwrap();
+    writeln!(out, "# TYPE {name} gauge").unwrap();
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |now| i64::try_from(now.as_secs()).unwrap_or(i64::MAX));
+    for (chain, not_after) in &*expiry {
+        let chain = chain
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        let remaining = not_after.saturating_sub(now);
+        writeln!(out, "{name}{{chain=\"{chain}\"}} {remaining}").unwrap();
+    }
+}
+
+fn render_runtime(out: &mut String, runtime: &Handle) {
+    let metrics = runtime.metrics();
+    let workers = metrics.num_workers() as u64;
+    gauge(
+        out,
+        "spx_runtime_workers",
+        "Worker threads in the Tokio runtime.",
+        workers,
+    );
+    gauge(
+        out,
+        "spx_runtime_alive_tasks",
+        "Tasks spawned on the Tokio runtime that haven't finished.",
+        metrics.num_alive_tasks() as u64,
+    );
+    // Tokio only reports this with `--cfg tokio_unstable`, so it is worked out from the threads
+    // the runtime has started instead.
+    gauge(
+        out,
+        "spx_runtime_blocking_threads",
+        "Threads in the Tokio runtime's blocking pool.",
+        RUNTIME_THREADS.load(Relaxed).saturating_sub(workers),
+    );
+}
+
+fn counter(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} counter").unwrap();
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} gauge").unwrap();
+    writeln!(out, "{name} {value}").unwrap();
+}
+
+/// Upper bounds of the histogram buckets, in milliseconds.
+const BUCKETS_MS: [u64; 11] = [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+struct Histogram {
+    /// The number of observations in each bucket, not including the ones before it.
+    buckets: [AtomicU64; BUCKETS_MS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS_MS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, value: Duration) {
+        let millis = value.as_millis();
+        if let Some(bucket) = BUCKETS_MS.iter().position(|&le| millis <= u128::from(le)) {
+            self.buckets[bucket].fetch_add(1, Relaxed);
+        }
+        self.count.fetch_add(1, Relaxed);
+        let micros = u64::try_from(value.as_micros()).unwrap_or(u64::MAX);
+        self.sum_micros.fetch_add(micros, Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} histogram").unwrap();
+        let mut cumulative = 0;
+        for (le, bucket) in BUCKETS_MS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Relaxed);
+            let le = Duration::from_millis(*le).as_secs_f64();
+            writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}").unwrap();
+        }
+        let count = self.count.load(Relaxed);
+        writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}").unwrap();
+        let sum = Duration::from_micros(self.sum_micros.load(Relaxed)).as_secs_f64();
+        writeln!(out, "{name}_sum {sum}").unwrap();
+        writeln!(out, "{name}_count {count}").unwrap();
+    }
+}
+
+#[test]
+fn certificate_expiry() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let in_a_day = i64::try_from(now).unwrap() + 24 * 60 * 60;
+    record_certificate_expiry("/etc/spx/\"a\".pem".to_owned(), in_a_day);
+    let out = render();
+    let line = out
+        .lines()
+        .find(|line| line.starts_with("spx_tls_certificate_expiry_seconds{"))
+        .unwrap();
+    let (labels, remaining) = line.rsplit_once(' ').unwrap();
+    assert_eq!(
+        labels,
+        r#"spx_tls_certificate_expiry_seconds{chain="/etc/spx/\"a\".pem"}"#
+    );
+    assert!((86_390..=86_400).contains(&remaining.parse::<i64>().unwrap()));
+}
+
+#[test]
+fn runtime_metrics() {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_time()
+        .on_thread_start(record_runtime_thread_start)
+        .on_thread_stop(record_runtime_thread_stop)
+        .build()
+        .unwrap();
+    let out = runtime.block_on(async {
+        tokio::task::spawn_blocking(|| {}).await.unwrap();
+        // Worker threads might not have started yet.
+        while RUNTIME_THREADS.load(Relaxed) < 3 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        render()
+    });
+    assert!(out.contains("spx_runtime_workers 2\n"));
+    assert!(out.contains("spx_runtime_alive_tasks "));
+    assert!(out.contains("spx_runtime_blocking_threads 1\n"));
+}
+
+#[test]
+fn histogram() {
+    let histogram = Histogram::new();
+    histogram.observe(Duration::from_millis(3));
+    histogram.observe(Duration::from_millis(30));
+    histogram.observe(Duration::from_secs(20));
+
+    let mut out = String::new();
+    histogram.render(&mut out, "test", "A test.");
+    assert!(out.contains("test_bucket{le=\"0.005\"} 1\n"));
+    assert!(out.contains("test_bucket{le=\"0.025\"} 1\n"));
+    assert!(out.contains("test_bucket{le=\"0.05\"} 2\n"));
+    assert!(out.contains("test_bucket{le=\"10\"} 2\n"));
+    assert!(out.contains("test_bucket{le=\"+Inf\"} 3\n"));
+    assert!(out.contains("test_count 3\n"));
+    assert!(out.contains("test_sum 20.033\n"));
+}
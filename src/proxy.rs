@@ -1,63 +1,294 @@
-use ::{
-    hyper::http::{self, Uri},
-    regex::Regex,
-    std::{
-        convert::Infallible,
-        error::Error,
-        fmt::{self, Display, Formatter},
-        future::Future,
-        io,
-        net::SocketAddr,
-        pin::Pin,
-        sync::Arc,
-        task::{self, Poll},
+use {
+    crate::{
+        access_log,
+        cache::{self, Cache},
+        compression::{self, DecompressError},
+        html,
+        load_shed::{self, LoadShedder},
+        metrics,
+        rate_limit::{self, RateLimiter},
+    },
+    ::{
+        anyhow::Context as _,
+        futures_util::{stream::FuturesUnordered, StreamExt as _, TryStreamExt as _},
+        hyper::{
+            body::{Bytes, HttpBody as _},
+            client::connect::{Connected, Connection},
+            http::{
+                self,
+                header::{self, HeaderMap, HeaderName, HeaderValue},
+                uri::{Authority, PathAndQuery, Scheme},
+                StatusCode, Uri,
+            },
+        },
+        hyper_rustls::{ConfigBuilderExt as _, HttpsConnector},
+        regex::Regex,
+        std::{
+            collections::HashMap,
+            convert::Infallible,
+            error::Error,
+            fmt::{self, Display, Formatter},
+            future::Future,
+            io,
+            net::{IpAddr, SocketAddr},
+            pin::Pin,
+            sync::Arc,
+            task::{self, Poll},
+            time::{Duration, Instant},
+        },
+        tokio::{
+            io::{AsyncRead, AsyncWrite, ReadBuf},
+            net::TcpStream,
+            time,
+        },
+        tokio_rustls::rustls,
+        tower_service::Service,
     },
-    tokio::net::TcpStream,
-    tower_service::Service,
 };
 
 pub(crate) struct Config {
     pub(crate) domain: String,
     pub(crate) resolver: resolver::Config,
-    pub(crate) deny_user_agents: Regex,
+    pub(crate) deny_user_agents: Option<Regex>,
+    pub(crate) upstream_tcp_user_timeout: Option<Duration>,
+    pub(crate) upstream_tcp_keepalive: Option<Duration>,
+    pub(crate) upstream_http2_prior_knowledge: bool,
+    pub(crate) preserve_header_case: bool,
+    pub(crate) no_sni_hosts: Vec<String>,
+    pub(crate) variants: HashMap<String, Variants>,
+    pub(crate) rewrite_html: bool,
+    /// The largest size compressed HTML may decompress to for its links to be rewritten. Larger
+    /// pages get an error response, since they could be decompression bombs.
+    pub(crate) max_decompressed_html: usize,
+    pub(crate) csp: Csp,
+    pub(crate) allowed_domains: Vec<String>,
+    pub(crate) merge_headers: Vec<HeaderName>,
+    pub(crate) allow_private_ips: bool,
+    pub(crate) own_ips: Vec<IpAddr>,
+    pub(crate) max_subdomain_labels: Option<usize>,
+    pub(crate) health_path: String,
+    pub(crate) metrics_path: Option<String>,
+    pub(crate) rewrite_referer: bool,
+    /// Whether to collapse repeated slashes and resolve dot-segments in upstream paths.
+    pub(crate) normalize_path: bool,
+    pub(crate) access_log_format: access_log::Format,
+    /// Requests taking longer than this in total are logged at warn level.
+    pub(crate) slow_request_threshold: Option<Duration>,
+    pub(crate) forwarding: Forwarding,
+    pub(crate) load_shedding: Option<load_shed::Config>,
+    pub(crate) upstream_timeout: Option<Duration>,
+    /// Whether to tell clients how long the upstream took to respond, in `X-Upstream-Time`.
+    pub(crate) upstream_time_header: bool,
+    /// The largest total size of upstream response headers to pass on to clients.
+    pub(crate) max_response_header_bytes: usize,
+    /// The `Strict-Transport-Security` header to send on HTTPS responses.
+    pub(crate) hsts: Option<HeaderValue>,
+    pub(crate) rate_limit: Option<rate_limit::Config>,
+    pub(crate) cache: Option<cache::Config>,
+    pub(crate) compression: Option<compression::Config>,
+    /// Bodies to use instead of the default ones for responses to requests rejected by policy.
+    pub(crate) responses: HashMap<StatusCode, CustomResponse>,
+}
+
+/// A configured body for responses with a certain status.
+pub(crate) struct CustomResponse {
+    pub(crate) content_type: HeaderValue,
+    pub(crate) body: Bytes,
+}
+
+/// What to do with `Content-Security-Policy` headers from upstreams.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Csp {
+    /// Pass them through unchanged.
+    Keep,
+    /// Remove them entirely.
+    Strip,
+    /// Rewrite the hosts they list to the proxied forms.
+    Rewrite,
+}
+
+/// How to treat forwarding headers sent by clients.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Forwarding {
+    /// Whether to keep the headers, instead of replacing them.
+    pub(crate) trusted: bool,
+    /// Whether to replace a trusted `X-Forwarded-For` chain with just the client's address, instead
+    /// of appending to it.
+    pub(crate) replace_chain: bool,
+    /// The most addresses to keep in `X-Forwarded-For`, dropping the earliest ones first.
+    pub(crate) max_chain: Option<usize>,
+}
+
+/// Alternative upstreams for a single site, one of which is chosen per client.
+pub(crate) struct Variants {
+    pub(crate) upstreams: Vec<String>,
+    pub(crate) key: VariantKey,
+}
+
+/// What identifies a client when choosing a variant.
+pub(crate) enum VariantKey {
+    Cookie(String),
+    Header(HeaderName),
 }
 
 #[derive(Clone)]
 pub(crate) struct Proxy {
     inner: Arc<ProxyInner>,
+    /// The client, if this proxy is serving a single connection.
+    peer: Option<Peer>,
+}
+
+/// The client at the other end of a connection.
+#[derive(Clone, Copy)]
+struct Peer {
+    /// The client's address, or `None` if it connected over a Unix socket.
+    addr: Option<SocketAddr>,
+    /// Whether the client connected to the HTTPS listener.
+    https: bool,
 }
 
 struct ProxyInner {
     domain: String,
-    deny_user_agents: Regex,
-    client: hyper::Client<hyper_rustls::HttpsConnector<Connector>>,
+    deny_user_agents: Option<Regex>,
+    variants: HashMap<String, Variants>,
+    rewrite_html: bool,
+    max_decompressed_html: usize,
+    csp: Csp,
+    allowed_domains: Vec<String>,
+    merge_headers: Vec<HeaderName>,
+    max_subdomain_labels: Option<usize>,
+    health_path: String,
+    metrics_path: Option<String>,
+    rewrite_referer: bool,
+    normalize_path: bool,
+    access_log_format: access_log::Format,
+    slow_request_threshold: Option<Duration>,
+    forwarding: Forwarding,
+    load_shedder: Option<Arc<LoadShedder>>,
+    upstream_timeout: Option<Duration>,
+    upstream_time_header: bool,
+    max_response_header_bytes: usize,
+    hsts: Option<HeaderValue>,
+    rate_limiter: Option<RateLimiter>,
+    cache: Option<Arc<Cache>>,
+    compression: Option<compression::Config>,
+    responses: HashMap<StatusCode, CustomResponse>,
+    client: hyper::Client<TlsConnector>,
+    /// A client that only speaks HTTP/1.1 to upstreams, for WebSocket handshakes.
+    upgrade_client: hyper::Client<TlsConnector>,
 }
 
 impl Proxy {
     pub(crate) fn new(config: Config) -> anyhow::Result<Self> {
         let http_connector = Connector {
             resolver: Resolver::new(config.resolver)?,
+            tcp_user_timeout: config.upstream_tcp_user_timeout,
+            tcp_keepalive: config.upstream_tcp_keepalive,
+            allow_private_ips: config.allow_private_ips,
+            own_ips: config.own_ips.into(),
+        };
+
+        let https_connector = |enable_sni, enable_http2| {
+            let mut tls_config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_webpki_roots()
+                .with_no_client_auth();
+            tls_config.enable_sni = enable_sni;
+
+            let builder = hyper_rustls::HttpsConnectorBuilder::new()
+                .with_tls_config(tls_config)
+                .https_or_http()
+                .enable_http1();
+            if enable_http2 {
+                builder
+                    .enable_http2()
+                    .wrap_connector(http_connector.clone())
+            } else {
+                builder.wrap_connector(http_connector.clone())
+            }
         };
 
-        let https_connector = hyper_rustls::HttpsConnectorBuilder::new()
-            .with_webpki_roots()
-            .https_or_http()
-            .enable_http1()
-            .enable_http2()
-            .wrap_connector(http_connector);
+        let no_sni_hosts: Arc<[String]> = config.no_sni_hosts.into();
+        let tls_connector = TlsConnector {
+            sni: https_connector(true, true),
+            no_sni: https_connector(false, true),
+            no_sni_hosts: no_sni_hosts.clone(),
+        };
+        // WebSockets can only be proxied over HTTP/1.1, so upstreams mustn't negotiate HTTP/2.
+        let upgrade_connector = TlsConnector {
+            sni: https_connector(true, false),
+            no_sni: https_connector(false, false),
+            no_sni_hosts,
+        };
 
-        let client = hyper::Client::builder().build(https_connector);
+        if config.csp == Csp::Strip {
+            log::warn!("upstream Content-Security-Policy headers will be removed");
+        }
+
+        let client = hyper::Client::builder()
+            .http2_only(config.upstream_http2_prior_knowledge)
+            .http1_preserve_header_case(config.preserve_header_case)
+            .build(tls_connector);
+        let upgrade_client = hyper::Client::builder()
+            .http1_preserve_header_case(config.preserve_header_case)
+            .build(upgrade_connector);
 
         let inner = Arc::new(ProxyInner {
             domain: config.domain,
             deny_user_agents: config.deny_user_agents,
+            variants: config.variants,
+            rewrite_html: config.rewrite_html,
+            max_decompressed_html: config.max_decompressed_html,
+            csp: config.csp,
+            allowed_domains: config.allowed_domains,
+            merge_headers: config.merge_headers,
+            max_subdomain_labels: config.max_subdomain_labels,
+            health_path: config.health_path,
+            metrics_path: config.metrics_path,
+            rewrite_referer: config.rewrite_referer,
+            normalize_path: config.normalize_path,
+            access_log_format: config.access_log_format,
+            slow_request_threshold: config.slow_request_threshold,
+            forwarding: config.forwarding,
+            load_shedder: config.load_shedding.map(LoadShedder::new),
+            upstream_timeout: config.upstream_timeout,
+            upstream_time_header: config.upstream_time_header,
+            max_response_header_bytes: config.max_response_header_bytes,
+            hsts: config.hsts,
+            rate_limiter: config.rate_limit.map(RateLimiter::new),
+            cache: config.cache.map(|config| Arc::new(Cache::new(config))),
+            compression: config.compression,
+            responses: config.responses,
             client,
+            upgrade_client,
         });
 
-        Ok(Proxy { inner })
+        Ok(Proxy { inner, peer: None })
+    }
+
+    /// Creates a proxy for serving a single connection from the given client.
+    pub(crate) fn for_peer(&self, addr: Option<SocketAddr>, https: bool) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            peer: Some(Peer { addr, https }),
+        }
+    }
+}
+
+impl Proxy {
+    /// Checks that the given URL can be reached through the same connection path as proxied
+    /// requests.
+    pub(crate) async fn probe(&self, url: Uri) -> anyhow::Result<()> {
+        let response = time::timeout(PROBE_TIMEOUT, self.inner.client.get(url))
+            .await
+            .context("timed out")??;
+        log::debug!("probe succeeded with status {}", response.status());
+        Ok(())
     }
 }
 
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
 impl Service<http::Request<hyper::Body>> for Proxy {
     type Response = http::Response<hyper::Body>;
     type Error = Infallible;
@@ -67,69 +298,1248 @@ impl Service<http::Request<hyper::Body>> for Proxy {
         Poll::Ready(Ok(()))
     }
 
-    fn call(&mut self, _req: http::Request<hyper::Body>) -> Self::Future {
-        todo!()
+    fn call(&mut self, req: http::Request<hyper::Body>) -> Self::Future {
+        let (inner, peer) = (self.inner.clone(), self.peer);
+        let mut access_log = access_log::Entry::start(
+            inner.access_log_format,
+            inner.slow_request_threshold,
+            peer.and_then(|peer| peer.addr),
+            &req,
+            request_host(&req),
+        );
+        let encoding = inner
+            .compression
+            .as_ref()
+            .and_then(|_| compression::negotiate(&req));
+        Box::pin(async move {
+            let hsts = inner
+                .hsts
+                .clone()
+                .filter(|_| peer.is_some_and(|peer| peer.https));
+            // Requests count as in flight until their response headers are ready.
+            let in_flight = inner.load_shedder.as_ref().map(LoadShedder::admit);
+            let mut response = match in_flight {
+                Some(None) => overloaded_response(&inner),
+                _ => handle(inner.clone(), peer, req, &mut access_log).await,
+            };
+            drop(in_flight);
+            // Compression happens last, so that HTML rewriting and caching see the upstream's
+            // uncompressed response.
+            if let (Some(config), Some(encoding)) = (&inner.compression, encoding) {
+                response = compression::compress(response, encoding, config);
+            }
+            if let Some(hsts) = hsts {
+                response
+                    .headers_mut()
+                    .insert(header::STRICT_TRANSPORT_SECURITY, hsts);
+            }
+            metrics::record_response(response.status());
+            Ok(match access_log {
+                Some(access_log) => access_log.finish(response),
+                None => response,
+            })
+        })
+    }
+}
+
+async fn handle(
+    inner: Arc<ProxyInner>,
+    peer: Option<Peer>,
+    mut req: http::Request<hyper::Body>,
+    access_log: &mut Option<access_log::Entry>,
+) -> http::Response<hyper::Body> {
+    metrics::record_request();
+
+    if let Some(problem) = conflicting_framing(req.headers()) {
+        log::warn!("rejecting request with {problem}");
+        return error_response(StatusCode::BAD_REQUEST, "conflicting message framing");
+    }
+
+    if let Some(response) = local_response(&inner, &req) {
+        return response;
+    }
+
+    if let Some(response) = rate_limited(&inner, peer) {
+        return response;
+    }
+
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .unwrap_or_default();
+    if inner
+        .deny_user_agents
+        .as_ref()
+        .is_some_and(|deny| deny.is_match(user_agent))
+    {
+        return policy_response(&inner, StatusCode::FORBIDDEN, "user agent denied");
+    }
+
+    let Some(host) = request_host(&req) else {
+        return error_response(StatusCode::BAD_REQUEST, "missing or invalid host");
+    };
+    let Some(upstream_host) = upstream_host(&host, &inner.domain) else {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            &format!("host must be a subdomain of {}", inner.domain),
+        );
+    };
+
+    if inner
+        .max_subdomain_labels
+        .is_some_and(|max| upstream_host.split('.').count() > max)
+    {
+        return error_response(StatusCode::BAD_REQUEST, "too many labels in upstream host");
+    }
+
+    if !is_allowed(upstream_host, &inner.allowed_domains) {
+        return policy_response(&inner, StatusCode::FORBIDDEN, "upstream host not allowed");
+    }
+
+    let upstream_host = match inner.variants.get(upstream_host) {
+        Some(variants) => choose_variant(variants, &req).unwrap_or(upstream_host),
+        None => upstream_host,
+    };
+
+    if let Some(access_log) = access_log {
+        access_log.set_upstream(upstream_host);
+    }
+
+    if is_own_domain(upstream_host, &inner.domain) {
+        return error_response(StatusCode::MISDIRECTED_REQUEST, "upstream is this proxy");
+    }
+
+    let cache_key = inner
+        .cache
+        .as_ref()
+        .and_then(|_| Cache::key(&req, upstream_host));
+    if let Some(response) = cached_response(inner.cache.as_deref(), cache_key.as_deref()) {
+        return response;
+    }
+
+    let upgrade = is_websocket_upgrade(&req).then(|| hyper::upgrade::on(&mut req));
+
+    // Stripped first, so that the client can't use `Connection` to remove the headers we add.
+    strip_hop_by_hop(req.headers_mut(), upgrade.is_some());
+
+    merge_headers(req.headers_mut(), &inner.merge_headers);
+
+    if inner.rewrite_referer {
+        rewrite_referer(req.headers_mut(), &inner.domain);
+    }
+
+    let original_host = original_host(&req);
+    forward_headers(req.headers_mut(), peer, original_host, inner.forwarding);
+
+    if rewrite_request(&mut req, upstream_host, inner.normalize_path).is_err() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid upstream host");
+    }
+
+    let mut response = match send_upstream(&inner, req, upstream_host, upgrade.is_some()).await {
+        Ok(response) => response,
+        Err(response) => return response,
+    };
+
+    if let Some(UpstreamAddr(addr)) = response.extensions().get() {
+        log::debug!("{upstream_host} responded from {addr}");
+    }
+
+    rewrite_response(&mut response, &inner.domain, inner.csp);
+
+    if let Some(upgrade) = upgrade {
+        tunnel(upgrade, &mut response);
+    }
+
+    if inner.rewrite_html && is_rewritable_html(&response) {
+        response = rewrite_html(response, &inner.domain, inner.max_decompressed_html).await;
+    }
+
+    if let Some(cache) = &inner.cache {
+        response = fill_cache(cache, cache_key, response);
+    }
+
+    if inner.metrics_path.is_some() {
+        response = response.map(|body| {
+            hyper::Body::wrap_stream(body.inspect_ok(|chunk| {
+                metrics::record_proxied_bytes(chunk.len());
+            }))
+        });
+    }
+
+    response
+}
+
+/// The response to requests rejected by load shedding.
+fn overloaded_response(inner: &ProxyInner) -> http::Response<hyper::Body> {
+    let mut response = policy_response(inner, StatusCode::SERVICE_UNAVAILABLE, "server overloaded");
+    let retry_after = HeaderValue::from_static("1");
+    response
+        .headers_mut()
+        .insert(header::RETRY_AFTER, retry_after);
+    response
+}
+
+/// Whether a response is HTML that is either uncompressed or compressed in a way that can be
+/// undone.
+fn is_rewritable_html(response: &http::Response<hyper::Body>) -> bool {
+    let headers = response.headers();
+    let html = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| {
+            let mime = content_type.split(';').next().unwrap_or_default().trim();
+            mime.eq_ignore_ascii_case("text/html")
+        });
+    let decodable = match headers.get(header::CONTENT_ENCODING) {
+        Some(encoding) => encoding == "identity" || compression::can_decompress(encoding),
+        None => true,
+    };
+    html && decodable
+}
+
+/// The largest HTML document that will have its links rewritten. Larger documents are passed
+/// through unchanged rather than being buffered in memory.
+const MAX_REWRITTEN_HTML: usize = 8 * 1024 * 1024;
+
+async fn rewrite_html(
+    response: http::Response<hyper::Body>,
+    domain: &str,
+    max_decompressed: usize,
+) -> http::Response<hyper::Body> {
+    let (mut parts, mut body) = response.into_parts();
+
+    let too_large = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok()?.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_REWRITTEN_HTML);
+    if too_large {
+        return http::Response::from_parts(parts, body);
+    }
+
+    let mut html = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                log::warn!("failed to read HTML response body: {e}");
+                return error_response(StatusCode::BAD_GATEWAY, "upstream response failed");
+            }
+        };
+        html.extend_from_slice(&chunk);
+
+        if html.len() > MAX_REWRITTEN_HTML {
+            let (mut sender, rest) = hyper::Body::channel();
+            tokio::spawn(async move {
+                if sender.send_data(html.into()).await.is_err() {
+                    return;
+                }
+                while let Some(chunk) = body.data().await {
+                    let Ok(chunk) = chunk else {
+                        sender.abort();
+                        return;
+                    };
+                    if sender.send_data(chunk).await.is_err() {
+                        return;
+                    }
+                }
+            });
+            return http::Response::from_parts(parts, rest);
+        }
+    }
+
+    // Compressed HTML is sent on decompressed, and recompressed later if compression is enabled.
+    let encoding = parts
+        .headers
+        .remove(header::CONTENT_ENCODING)
+        .filter(|encoding| encoding != "identity");
+    let html = match encoding {
+        Some(encoding) => match compression::decompress(&encoding, &html, max_decompressed) {
+            Ok(decompressed) => decompressed,
+            Err(DecompressError::Invalid) => {
+                log::debug!("not rewriting HTML that failed to decompress");
+                parts.headers.insert(header::CONTENT_ENCODING, encoding);
+                return http::Response::from_parts(parts, hyper::Body::from(html));
+            }
+            Err(DecompressError::TooLarge) => {
+                log::warn!("HTML response decompressed to more than {max_decompressed} bytes");
+                return error_response(StatusCode::BAD_GATEWAY, "upstream response too large");
+            }
+        },
+        None => html,
+    };
+
+    let html = html::rewrite_links(&html, |url| proxied_url(url, domain));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    http::Response::from_parts(parts, hyper::Body::from(html))
+}
+
+/// Makes an upstream response refer to the proxied forms of URLs.
+fn rewrite_response(response: &mut http::Response<hyper::Body>, domain: &str, csp: Csp) {
+    let upgrade = response.status() == StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+
+    strip_hop_by_hop(headers, upgrade);
+
+    if let Some(location) = headers.get(header::LOCATION) {
+        let proxied = location
+            .to_str()
+            .ok()
+            .and_then(|location| proxied_url(location, domain))
+            .and_then(|proxied| HeaderValue::try_from(proxied).ok());
+        if let Some(proxied) = proxied {
+            headers.insert(header::LOCATION, proxied);
+        }
+    }
+
+    if let header::Entry::Occupied(mut cookies) = headers.entry(header::SET_COOKIE) {
+        for cookie in cookies.iter_mut() {
+            let proxied = cookie
+                .to_str()
+                .ok()
+                .and_then(|cookie| proxied_cookie(cookie, domain))
+                .and_then(|proxied| HeaderValue::try_from(proxied).ok());
+            if let Some(proxied) = proxied {
+                *cookie = proxied;
+            }
+        }
+    }
+
+    let csp_headers = [
+        header::CONTENT_SECURITY_POLICY,
+        header::CONTENT_SECURITY_POLICY_REPORT_ONLY,
+    ];
+    match csp {
+        Csp::Keep => {}
+        Csp::Strip => {
+            for name in csp_headers {
+                headers.remove(name);
+            }
+        }
+        Csp::Rewrite => {
+            for name in csp_headers {
+                let header::Entry::Occupied(mut policies) = headers.entry(name) else {
+                    continue;
+                };
+                for policy in policies.iter_mut() {
+                    let proxied = policy
+                        .to_str()
+                        .ok()
+                        .map(|policy| proxied_csp(policy, domain))
+                        .and_then(|proxied| HeaderValue::try_from(proxied).ok());
+                    if let Some(proxied) = proxied {
+                        *policy = proxied;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rewrites the host sources in a `Content-Security-Policy` header value to their proxied forms,
+/// for example `script-src 'self' https://*.rust-lang.org` becomes
+/// `script-src 'self' https://*.rust-lang.org.example.com`.
+fn proxied_csp(policy: &str, domain: &str) -> String {
+    // Directives whose values aren't lists of sources.
+    const NON_SOURCE_DIRECTIVES: [&str; 7] = [
+        "sandbox",
+        "report-to",
+        "trusted-types",
+        "require-trusted-types-for",
+        "plugin-types",
+        "upgrade-insecure-requests",
+        "block-all-mixed-content",
+    ];
+
+    policy
+        .split(';')
+        .filter_map(|directive| {
+            let mut tokens = directive.split_ascii_whitespace();
+            let name = tokens.next()?;
+            let non_source = NON_SOURCE_DIRECTIVES
+                .iter()
+                .any(|non_source| name.eq_ignore_ascii_case(non_source));
+            let values = tokens.map(|value| {
+                if non_source {
+                    value.to_owned()
+                } else {
+                    proxied_csp_source(value, domain)
+                }
+            });
+            Some(
+                std::iter::once(name.to_owned())
+                    .chain(values)
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+fn proxied_csp_source(source: &str, domain: &str) -> String {
+    // Keywords like `'self'`, nonces, hashes and scheme sources like `data:` stay the same.
+    if source.starts_with('\'') || source.ends_with(':') || source == "*" {
+        return source.to_owned();
+    }
+    let (scheme, rest) = match source.split_once("://") {
+        Some((scheme, rest)) => (&source[..scheme.len() + 3], rest),
+        None => ("", source),
+    };
+    let host_end = rest.find(['/', ':']).unwrap_or(rest.len());
+    let (host, path) = rest.split_at(host_end);
+    // Relative URLs (as in `report-uri`) and sources with ports can't be proxied.
+    if host.is_empty() || path.starts_with(':') {
+        return source.to_owned();
+    }
+    format!("{scheme}{host}.{domain}{path}")
+}
+
+/// Rewrites the `Domain` attribute of a `Set-Cookie` header value to the proxied domain,
+/// returning `None` if there is nothing to change.
+fn proxied_cookie(cookie: &str, domain: &str) -> Option<String> {
+    let mut changed = false;
+    let attributes = cookie
+        .split(';')
+        .enumerate()
+        .map(|(i, attribute)| {
+            // The first pair is the cookie itself rather than an attribute.
+            let Some((name, value)) = attribute.split_once('=').filter(|_| i != 0) else {
+                return attribute.to_owned();
+            };
+            if !name.trim().eq_ignore_ascii_case("domain") {
+                return attribute.to_owned();
+            }
+            changed = true;
+            let cookie_domain = value.trim().trim_start_matches('.');
+            format!("{name}={cookie_domain}.{domain}")
+        })
+        .collect::<Vec<_>>();
+    changed.then(|| attributes.join(";"))
+}
+
+/// Converts an absolute URL to the URL that accesses it through the proxy, e.g.
+/// `https://static.rust-lang.org/foo` becomes `https://static.rust-lang.org.example.com/foo`.
+///
+/// Returns `None` if the URL is relative, malformed or otherwise can't be proxied.
+fn proxied_url(url: &str, domain: &str) -> Option<String> {
+    let url = url.parse::<Uri>().ok()?;
+    let scheme = url.scheme_str()?;
+    if !matches!(scheme, "http" | "https") {
+        return None;
+    }
+    let authority = url.authority()?;
+    if authority.port().is_some() || authority.as_str().contains('@') {
+        return None;
+    }
+    let path_and_query = url.path_and_query().map_or("/", PathAndQuery::as_str);
+    Some(format!(
+        "{scheme}://{}.{domain}{path_and_query}",
+        authority.host()
+    ))
+}
+
+/// Removes headers that only apply to a single connection, and so must not be forwarded. If
+/// `upgrade` is set, the `Upgrade` header is kept (along with `Connection: upgrade`), since it must
+/// reach the other side for a WebSocket handshake to succeed.
+fn strip_hop_by_hop(headers: &mut HeaderMap, upgrade: bool) {
+    let upgrade = upgrade
+        .then(|| headers.get(header::UPGRADE).cloned())
+        .flatten();
+
+    let listed = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::try_from(name.trim()).ok())
+        .collect::<Vec<_>>();
+    for name in listed {
+        headers.remove(name);
+    }
+
+    // `TE: trailers` is allowed through, since gRPC upstreams require it.
+    if headers.get(header::TE).is_some_and(|te| te != "trailers") {
+        headers.remove(header::TE);
+    }
+
+    for name in [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TRAILER,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ] {
+        headers.remove(name);
+    }
+
+    if let Some(upgrade) = upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, upgrade);
+    }
+}
+
+/// Whether a request is the start of a WebSocket handshake.
+fn is_websocket_upgrade(req: &http::Request<hyper::Body>) -> bool {
+    let has_token = |name, token: &str| {
+        req.headers()
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case(token))
+    };
+    req.version() == http::Version::HTTP_11
+        && has_token(header::CONNECTION, "upgrade")
+        && has_token(header::UPGRADE, "websocket")
+}
+
+/// If the upstream agreed to switch protocols, copies bytes between the client and the upstream
+/// in the background once the response has been sent.
+fn tunnel(client: hyper::upgrade::OnUpgrade, response: &mut http::Response<hyper::Body>) {
+    if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+        return;
+    }
+    let upstream = hyper::upgrade::on(response);
+    tokio::spawn(async move {
+        let (mut client, mut upstream) = match tokio::try_join!(client, upstream) {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                log::debug!("failed to upgrade connection: {e}");
+                return;
+            }
+        };
+        if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            log::debug!("upgraded connection failed: {e}");
+        }
+    });
+}
+
+/// Tells the upstream about the client with `X-Forwarded-*` headers. Unless the headers sent by
+/// the client are trusted, they are replaced, since otherwise clients could spoof them.
+fn forward_headers(
+    headers: &mut HeaderMap,
+    peer: Option<Peer>,
+    host: Option<HeaderValue>,
+    forwarding: Forwarding,
+) {
+    const X_FORWARDED_FOR: &str = "x-forwarded-for";
+    const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+    const X_FORWARDED_HOST: &str = "x-forwarded-host";
+
+    if !forwarding.trusted {
+        headers.remove(header::FORWARDED);
+        headers.remove(X_FORWARDED_FOR);
+        headers.remove(X_FORWARDED_PROTO);
+        headers.remove(X_FORWARDED_HOST);
+    }
+
+    let Some(peer) = peer else {
+        return;
+    };
+
+    let mut chain = Vec::new();
+    if !forwarding.replace_chain {
+        chain.extend(
+            headers
+                .get_all(X_FORWARDED_FOR)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|entry| !entry.is_empty())
+                .map(str::to_owned),
+        );
+    }
+    chain.extend(peer.addr.map(|addr| addr.ip().to_string()));
+    if let Some(max) = forwarding.max_chain {
+        chain.drain(..chain.len().saturating_sub(max));
+    }
+    if chain.is_empty() {
+        headers.remove(X_FORWARDED_FOR);
+    } else {
+        // Parts of valid header values and IP addresses joined by commas are always valid.
+        let chain = HeaderValue::try_from(chain.join(", ")).unwrap();
+        headers.insert(X_FORWARDED_FOR, chain);
+    }
+
+    if !headers.contains_key(X_FORWARDED_PROTO) {
+        let proto = if peer.https { "https" } else { "http" };
+        headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+    }
+    if !headers.contains_key(X_FORWARDED_HOST) {
+        if let Some(host) = host {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+    }
+}
+
+/// Makes the `Referer` header of a request refer to the upstream form of the URL, if it refers to
+/// a proxied page.
+fn rewrite_referer(headers: &mut HeaderMap, domain: &str) {
+    let upstream = headers
+        .get(header::REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .and_then(|referer| upstream_url(referer, domain))
+        .and_then(|upstream| HeaderValue::try_from(upstream).ok());
+    if let Some(upstream) = upstream {
+        headers.insert(header::REFERER, upstream);
+    }
+}
+
+/// The inverse of [`proxied_url`]: converts the URL of a proxied page back into the URL of the
+/// upstream page, for example `http://docs.rs.example.com/foo` becomes `https://docs.rs/foo`.
+/// Upstreams are always reached over HTTPS, so the result always uses that scheme.
+///
+/// Returns `None` if the URL doesn't refer to a proxied page.
+fn upstream_url(url: &str, domain: &str) -> Option<String> {
+    let url = url.parse::<Uri>().ok()?;
+    if !matches!(url.scheme_str()?, "http" | "https") {
+        return None;
+    }
+    let authority = url.authority()?;
+    if authority.as_str().contains('@') {
+        return None;
+    }
+    let host = upstream_host(authority.host(), domain)?;
+    let path_and_query = url.path_and_query().map_or("/", PathAndQuery::as_str);
+    Some(format!("https://{host}{path_and_query}"))
+}
+
+/// Picks the variant for the client making the request. The same key always maps to the same
+/// variant; clients without a key get the first one.
+fn choose_variant<'a>(variants: &'a Variants, req: &http::Request<hyper::Body>) -> Option<&'a str> {
+    let key = match &variants.key {
+        VariantKey::Cookie(name) => cookie(req, name),
+        VariantKey::Header(name) => req.headers().get(name).map(HeaderValue::as_bytes),
+    };
+    let index = match key {
+        Some(key) => usize::try_from(fnv1a(key) % variants.upstreams.len() as u64).unwrap(),
+        None => 0,
+    };
+    variants.upstreams.get(index).map(String::as_str)
+}
+
+/// The 64-bit FNV-1a hash. Unlike the standard library's hashers, its output is fixed, so clients
+/// keep their variants across upgrades of the proxy.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn cookie<'a>(req: &'a http::Request<hyper::Body>, name: &str) -> Option<&'a [u8]> {
+    req.headers()
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookies| cookies.to_str().ok())
+        .flat_map(|cookies| cookies.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|&(cookie_name, _)| cookie_name == name)
+        .map(|(_, value)| value.as_bytes())
+}
+
+/// Turns a request made to the proxy into the equivalent request to the upstream host.
+fn rewrite_request(
+    req: &mut http::Request<hyper::Body>,
+    upstream_host: &str,
+    normalize: bool,
+) -> Result<(), http::Error> {
+    let mut uri_parts = req.uri().clone().into_parts();
+    uri_parts.scheme = Some(Scheme::HTTPS);
+    uri_parts.authority = Some(upstream_host.parse()?);
+    match &uri_parts.path_and_query {
+        None => uri_parts.path_and_query = Some(PathAndQuery::from_static("/")),
+        Some(path_and_query) if normalize && path_and_query.path().starts_with('/') => {
+            let mut normalized = normalize_path(path_and_query.path());
+            if let Some(query) = path_and_query.query() {
+                normalized.push('?');
+                normalized.push_str(query);
+            }
+            uri_parts.path_and_query = Some(PathAndQuery::try_from(normalized)?);
+        }
+        Some(_) => {}
+    }
+    *req.uri_mut() = Uri::from_parts(uri_parts)?;
+
+    // The `Host` header must match the URI's authority, or virtual-hosted upstreams will see the
+    // proxy's domain.
+    req.headers_mut()
+        .insert(header::HOST, HeaderValue::from_str(upstream_host)?);
+
+    // The client picks whichever HTTP version it negotiated with the upstream.
+    *req.version_mut() = http::Version::HTTP_11;
+
+    Ok(())
+}
+
+/// Collapses repeated slashes and resolves `.` and `..` segments (including percent-encoded ones)
+/// in an absolute path, so that upstreams can't be confused by paths that only look like they are
+/// under some prefix. `..` at the root stays at the root.
+fn normalize_path(path: &str) -> String {
+    let mut segments = Vec::new();
+    let mut trailing_slash = false;
+    for segment in path.split('/') {
+        trailing_slash = true;
+        match &*segment.to_ascii_lowercase().replace("%2e", ".") {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            _ => {
+                segments.push(segment);
+                trailing_slash = false;
+            }
+        }
+    }
+
+    let mut normalized = String::with_capacity(path.len());
+    for segment in segments {
+        normalized.push('/');
+        normalized.push_str(segment);
+    }
+    if normalized.is_empty() || trailing_slash {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// Gets the host the client requested exactly as it was sent, including any port.
+fn original_host(req: &http::Request<hyper::Body>) -> Option<HeaderValue> {
+    match req.headers().get(header::HOST) {
+        Some(host) => Some(host.clone()),
+        None => HeaderValue::try_from(req.uri().authority()?.as_str()).ok(),
+    }
+}
+
+/// Gets the host the client requested in canonical form: lowercase, without the port and without
+/// a trailing dot. This keeps equivalent hosts from being treated as different upstreams.
+fn request_host(req: &http::Request<hyper::Body>) -> Option<String> {
+    let authority = match req.headers().get(header::HOST) {
+        Some(host) => host.to_str().ok()?.parse::<Authority>().ok()?,
+        None => req.uri().authority()?.clone(),
+    };
+    let host = authority.host();
+    Some(host.strip_suffix('.').unwrap_or(host).to_ascii_lowercase())
+}
+
+/// Recovers the upstream host from a requested host by stripping the proxy's domain, e.g.
+/// `www.rust-lang.org.example.com` becomes `www.rust-lang.org`.
+fn upstream_host<'a>(host: &'a str, domain: &str) -> Option<&'a str> {
+    let split = host.len().checked_sub(domain.len() + 1)?;
+    let (upstream, suffix) = (host.get(..split)?, &host[split..]);
+    (suffix.starts_with('.') && suffix[1..].eq_ignore_ascii_case(domain) && !upstream.is_empty())
+        .then_some(upstream)
+}
+
+/// Whether the upstream host is the proxy's own domain or one of its subdomains, which would make
+/// the proxy connect to itself.
+fn is_own_domain(host: &str, domain: &str) -> bool {
+    host.eq_ignore_ascii_case(domain) || upstream_host(host, domain).is_some()
+}
+
+/// Describes why a request to an upstream failed.
+fn upstream_error_response(e: &hyper::Error) -> http::Response<hyper::Body> {
+    let (status, message) = match connector_error(e) {
+        Some(ConnectorError::NoHost(_)) => (StatusCode::BAD_REQUEST, "missing upstream host"),
+        Some(ConnectorError::Dns(resolver::Error::Private)) => (
+            StatusCode::FORBIDDEN,
+            "upstream host only resolves to private addresses",
+        ),
+        Some(ConnectorError::Dns(_)) => {
+            (StatusCode::BAD_GATEWAY, "failed to resolve upstream host")
+        }
+        Some(ConnectorError::Tcp(e)) if e.kind() == io::ErrorKind::TimedOut => (
+            StatusCode::GATEWAY_TIMEOUT,
+            "timed out connecting to upstream",
+        ),
+        Some(ConnectorError::Tcp(_)) => (StatusCode::BAD_GATEWAY, "failed to connect to upstream"),
+        Some(ConnectorError::Loop) => (StatusCode::MISDIRECTED_REQUEST, "upstream is this proxy"),
+        None => (StatusCode::BAD_GATEWAY, "upstream request failed"),
+    };
+    error_response(status, message)
+}
+
+/// Finds the error from our connector that caused a client error, if there is one.
+fn connector_error(e: &hyper::Error) -> Option<&ConnectorError> {
+    let mut source = e.source();
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref() {
+            return Some(e);
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// Coalesces repeated occurrences of each of the given headers into a single comma-separated value.
+fn merge_headers(headers: &mut HeaderMap, names: &[HeaderName]) {
+    for name in names {
+        let header::Entry::Occupied(mut entry) = headers.entry(name) else {
+            continue;
+        };
+        if entry.iter().nth(1).is_none() {
+            continue;
+        }
+        let mut merged = Vec::new();
+        for value in entry.iter() {
+            if !merged.is_empty() {
+                merged.extend_from_slice(b", ");
+            }
+            merged.extend_from_slice(value.as_bytes());
+        }
+        // Joining valid header values with a comma always produces a valid header value.
+        entry.insert(HeaderValue::from_bytes(&merged).unwrap());
+    }
+}
+
+/// Whether the upstream host is a subdomain of (or equal to) one of the allowed domains. An empty
+/// list allows every host.
+fn is_allowed(host: &str, allowed_domains: &[String]) -> bool {
+    allowed_domains.is_empty()
+        || allowed_domains.iter().any(|allowed| {
+            host.eq_ignore_ascii_case(allowed) || upstream_host(host, allowed).is_some()
+        })
+}
+
+/// Sends a request upstream, returning the error response to send to the client if it fails.
+async fn send_upstream(
+    inner: &ProxyInner,
+    req: http::Request<hyper::Body>,
+    upstream_host: &str,
+    upgrade: bool,
+) -> Result<http::Response<hyper::Body>, http::Response<hyper::Body>> {
+    let start = Instant::now();
+    // The timeout only covers receiving the response headers; bodies may take as long as they need.
+    let client = if upgrade {
+        &inner.upgrade_client
+    } else {
+        &inner.client
+    };
+    let request = client.request(req);
+    let result = match inner.upstream_timeout {
+        Some(timeout) => time::timeout(timeout, request).await,
+        None => Ok(request.await),
+    };
+    let mut response = match result {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            log::warn!("request to {upstream_host} failed: {e}");
+            return Err(upstream_error_response(&e));
+        }
+        Err(_) => {
+            log::warn!("request to {upstream_host} timed out");
+            return Err(error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                "upstream timed out",
+            ));
+        }
+    };
+    let elapsed = start.elapsed();
+    metrics::record_upstream_latency(elapsed);
+    if let Some(problem) = conflicting_framing(response.headers()) {
+        log::warn!("{upstream_host} responded with {problem}");
+        return Err(error_response(
+            StatusCode::BAD_GATEWAY,
+            "upstream sent conflicting message framing",
+        ));
+    }
+    let header_bytes = header_bytes(response.headers());
+    if header_bytes > inner.max_response_header_bytes {
+        log::warn!("{upstream_host} responded with {header_bytes} bytes of headers");
+        return Err(error_response(
+            StatusCode::BAD_GATEWAY,
+            "upstream response headers too large",
+        ));
+    }
+    if inner.upstream_time_header {
+        let millis = u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX);
+        response
+            .headers_mut()
+            .insert(X_UPSTREAM_TIME, HeaderValue::from(millis));
+    }
+    Ok(response)
+}
+
+const X_UPSTREAM_TIME: &str = "x-upstream-time";
+
+const X_CACHE: &str = "x-cache";
+
+fn cached_response(
+    cache: Option<&Cache>,
+    key: Option<&str>,
+) -> Option<http::Response<hyper::Body>> {
+    let cache = cache?;
+    let mut response = cache.get(key?, Instant::now())?;
+    if cache.status_header() {
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static("HIT"));
+    }
+    Some(response)
+}
+
+/// Caches an upstream response if the request could have been served from the cache.
+fn fill_cache(
+    cache: &Arc<Cache>,
+    key: Option<String>,
+    mut response: http::Response<hyper::Body>,
+) -> http::Response<hyper::Body> {
+    let status = match key {
+        Some(key) => {
+            response = cache.fill(key, response);
+            "MISS"
+        }
+        None => "BYPASS",
+    };
+    if cache.status_header() {
+        response
+            .headers_mut()
+            .insert(X_CACHE, HeaderValue::from_static(status));
+    }
+    response
+}
+
+/// Answers health checks and metrics requests directly, without touching DNS or any upstream.
+fn local_response(
+    inner: &ProxyInner,
+    req: &http::Request<hyper::Body>,
+) -> Option<http::Response<hyper::Body>> {
+    let path = req.uri().path();
+    if (path != inner.health_path && inner.metrics_path.as_deref() != Some(path))
+        || !request_host(req).is_some_and(|host| host.eq_ignore_ascii_case(&inner.domain))
+    {
+        return None;
+    }
+    let (content_type, body) = if path == inner.health_path {
+        ("text/plain; charset=utf-8", "ok\n".to_owned())
+    } else {
+        ("text/plain; version=0.0.4", metrics::render())
+    };
+    let response = http::Response::builder()
+        .header(header::CONTENT_TYPE, content_type)
+        .body(hyper::Body::from(body))
+        .unwrap();
+    Some(response)
+}
+
+/// Returns a 429 response if the client has made too many requests recently. Clients without an
+/// address, which connected over a Unix socket, aren't limited.
+fn rate_limited(inner: &ProxyInner, peer: Option<Peer>) -> Option<http::Response<hyper::Body>> {
+    let (limiter, addr) = (inner.rate_limiter.as_ref()?, peer?.addr?);
+    let wait = limiter.check(addr.ip(), Instant::now()).err()?;
+    let mut response = policy_response(inner, StatusCode::TOO_MANY_REQUESTS, "too many requests");
+    let retry_after = wait.as_secs_f64().ceil().to_string();
+    response.headers_mut().insert(
+        header::RETRY_AFTER,
+        HeaderValue::try_from(retry_after).unwrap(),
+    );
+    Some(response)
+}
+
+/// Describes why the headers of a message make its length ambiguous, if they do. Intermediaries
+/// that disagree on where such a message ends can be used to smuggle requests past each other.
+fn conflicting_framing(headers: &HeaderMap) -> Option<&'static str> {
+    let mut lengths = headers
+        .get_all(header::CONTENT_LENGTH)
+        .iter()
+        .flat_map(|value| value.as_bytes().split(|&byte| byte == b','))
+        .map(<[u8]>::trim_ascii);
+    let first = lengths.next()?;
+    if lengths.any(|length| length != first) {
+        return Some("conflicting Content-Length headers");
+    }
+    if headers.contains_key(header::TRANSFER_ENCODING) {
+        return Some("both Content-Length and Transfer-Encoding");
+    }
+    None
+}
+
+/// The size of headers as they would be sent over HTTP/1.1.
+fn header_bytes(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        // The name and value are separated by `: ` and followed by CRLF.
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
+}
+
+/// A response to a request rejected by policy, with the configured body for its status if there is
+/// one.
+fn policy_response(
+    inner: &ProxyInner,
+    status: StatusCode,
+    message: &str,
+) -> http::Response<hyper::Body> {
+    let Some(custom) = inner.responses.get(&status) else {
+        return error_response(status, message);
+    };
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, custom.content_type.clone())
+        .body(hyper::Body::from(custom.body.clone()))
+        .unwrap()
+}
+
+fn error_response(status: StatusCode, message: &str) -> http::Response<hyper::Body> {
+    http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(hyper::Body::from(format!("{message}\n")))
+        .unwrap()
+}
+
+/// Connects to upstreams over TLS if necessary, omitting SNI for the configured hosts.
+#[derive(Clone)]
+struct TlsConnector {
+    sni: HttpsConnector<Connector>,
+    no_sni: HttpsConnector<Connector>,
+    no_sni_hosts: Arc<[String]>,
+}
+
+impl Service<Uri> for TlsConnector {
+    type Response = <HttpsConnector<Connector> as Service<Uri>>::Response;
+    type Error = <HttpsConnector<Connector> as Service<Uri>>::Error;
+    type Future = <HttpsConnector<Connector> as Service<Uri>>::Future;
+
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default();
+        if self
+            .no_sni_hosts
+            .iter()
+            .any(|no_sni_host| no_sni_host.eq_ignore_ascii_case(host))
+        {
+            self.no_sni.call(uri)
+        } else {
+            self.sni.call(uri)
+        }
     }
 }
 
 #[derive(Clone)]
 struct Connector {
     resolver: Resolver,
+    tcp_user_timeout: Option<Duration>,
+    tcp_keepalive: Option<Duration>,
+    allow_private_ips: bool,
+    own_ips: Arc<[IpAddr]>,
 }
 
 impl Service<Uri> for Connector {
-    type Response = TcpStream;
+    type Response = UpstreamStream;
     type Error = ConnectorError;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
-    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        Poll::Ready(Ok(()))
+    fn poll_ready(&mut self, _cx: &mut task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let this = self.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or(ConnectorError::NoHost(NoHostError))?;
+            let port = uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
+                Some("https") => 443,
+                _ => 80,
+            });
+
+            let addresses = this.resolver.resolve(host).await.map_err(|e| {
+                metrics::record_dns_failure();
+                ConnectorError::Dns(e)
+            })?;
+            let addresses = if this.allow_private_ips {
+                addresses.collect()
+            } else {
+                resolver::public_only(host, addresses).map_err(ConnectorError::Dns)?
+            };
+            if addresses.iter().any(|ip| this.own_ips.contains(ip)) {
+                return Err(ConnectorError::Loop);
+            }
+            let addresses: Vec<_> = addresses
+                .into_iter()
+                .map(|ip| SocketAddr::new(ip, port))
+                .collect();
+
+            let tcp_stream = connect_happy_eyeballs(&addresses).await.map_err(|e| {
+                metrics::record_upstream_connect_failure();
+                ConnectorError::Tcp(e)
+            })?;
+
+            if let Err(e) = tcp_stream.set_nodelay(true) {
+                log::debug!("failed to set TCP_NODELAY: {e}");
+            }
+            if let Some(timeout) = this.tcp_user_timeout {
+                set_tcp_user_timeout(&tcp_stream, timeout);
+            }
+            if let Some(time) = this.tcp_keepalive {
+                let keepalive = socket2::TcpKeepalive::new().with_time(time);
+                if let Err(e) = socket2::SockRef::from(&tcp_stream).set_tcp_keepalive(&keepalive) {
+                    log::debug!("failed to enable TCP keepalive: {e}");
+                }
+            }
+
+            let addr = tcp_stream.peer_addr().map_err(ConnectorError::Tcp)?;
+            log::debug!("connected to {host} at {addr}");
+
+            Ok(UpstreamStream { tcp_stream, addr })
+        })
+    }
+}
+
+/// The address of the upstream server that a response came from, available in the response's
+/// extensions.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct UpstreamAddr(pub(crate) SocketAddr);
+
+struct UpstreamStream {
+    tcp_stream: TcpStream,
+    addr: SocketAddr,
+}
+
+impl Connection for UpstreamStream {
+    fn connected(&self) -> Connected {
+        self.tcp_stream.connected().extra(UpstreamAddr(self.addr))
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.tcp_stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.tcp_stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut task::Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.tcp_stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.tcp_stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.tcp_stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.tcp_stream).poll_shutdown(cx)
+    }
+}
+
+/// How long to wait for a connection attempt before starting the next one in parallel.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connects to whichever address accepts first, like Happy Eyeballs (RFC 8305): attempts are
+/// started one at a time alternating between IPv6 and IPv4, with each starting once the previous
+/// one fails or takes too long. This avoids waiting for a black-holed address to time out.
+async fn connect_happy_eyeballs(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut remaining = interleave_families(addresses).into_iter();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+
+    // Each iteration starts an attempt, then waits for one to fail or for the delay to pass.
+    loop {
+        if let Some(addr) = remaining.next() {
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        }
+        if attempts.is_empty() {
+            break;
+        }
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(tcp_stream) => return Ok(tcp_stream),
+                Err(e) => {
+                    log::debug!("failed to connect to {addr}: {e}");
+                    last_error = Some(e);
+                }
+            },
+            () = time::sleep(CONNECTION_ATTEMPT_DELAY), if remaining.len() != 0 => {}
+        }
     }
 
-    fn call(&mut self, uri: Uri) -> Self::Future {
-        let this = self.clone();
-        Box::pin(async move {
-            let host = uri.host().ok_or(ConnectorError::NoHost(NoHostError))?;
-            let port = uri.port_u16().unwrap_or_else(|| match uri.scheme_str() {
-                Some("https") => 443,
-                _ => 80,
-            });
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+    }))
+}
 
-            let addresses: Vec<_> = this
-                .resolver
-                .resolve(host)
-                .await
-                .map_err(ConnectorError::Dns)?
-                .map(|ip| SocketAddr::new(ip, port))
-                .collect();
+/// Reorders addresses to alternate between address families, starting with the family of the
+/// first address and otherwise keeping the resolver's order.
+fn interleave_families(addresses: &[SocketAddr]) -> Vec<SocketAddr> {
+    let Some(first) = addresses.first() else {
+        return Vec::new();
+    };
+    let (preferred, other): (Vec<SocketAddr>, Vec<SocketAddr>) = addresses
+        .iter()
+        .partition(|addr| addr.is_ipv4() == first.is_ipv4());
+    let mut interleaved = Vec::with_capacity(addresses.len());
+    let (mut preferred, mut other) = (preferred.into_iter(), other.into_iter());
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => break interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
 
-            TcpStream::connect(&*addresses)
-                .await
-                .map_err(ConnectorError::Tcp)
-        })
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn set_tcp_user_timeout(tcp_stream: &TcpStream, timeout: Duration) {
+    if let Err(e) = socket2::SockRef::from(tcp_stream).set_tcp_user_timeout(Some(timeout)) {
+        log::debug!("failed to set TCP user timeout: {e}");
     }
 }
 
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn set_tcp_user_timeout(_tcp_stream: &TcpStream, _timeout: Duration) {}
+
 #[derive(Debug)]
 enum ConnectorError {
     NoHost(NoHostError),
     Dns(resolver::Error),
     Tcp(io::Error),
+    /// The upstream resolved to one of the proxy's own addresses.
+    Loop,
 }
 
 impl Display for ConnectorError {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.write_str("failed to connect to URI")
+        match self {
+            Self::Loop => f.write_str("URI resolved to the proxy itself"),
+            _ => f.write_str("failed to connect to URI"),
+        }
     }
 }
 
 impl Error for ConnectorError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
-        Some(match self {
-            Self::NoHost(e) => e,
-            Self::Dns(e) => e,
-            Self::Tcp(e) => e,
-        })
+        match self {
+            Self::NoHost(e) => Some(e),
+            Self::Dns(e) => Some(e),
+            Self::Tcp(e) => Some(e),
+            Self::Loop => None,
+        }
     }
 }
 
@@ -148,99 +1558,1393 @@ pub(crate) mod resolver {
     use ::{
         anyhow::Context as _,
         std::{
+            collections::HashMap,
             error::Error as StdError,
             fmt::{self, Display, Formatter},
+            future::Future,
             io,
-            net::IpAddr,
-            sync::Arc,
+            net::{IpAddr, Ipv4Addr, ToSocketAddrs as _},
+            pin::Pin,
+            sync::{Arc, Mutex},
+            time::{Duration, Instant},
+            vec,
         },
         tokio::net,
+        trust_dns_resolver::error::ResolveErrorKind,
     };
 
     pub(crate) enum Config {
         System,
+        ResolvConf,
         TrustDns(trust_dns_resolver::config::ResolverConfig),
+        Chain(Vec<Config>),
+        /// Fixed addresses for some hosts, like `/etc/hosts`, falling back to another resolver for
+        /// the rest.
+        Hosts {
+            hosts: HashMap<String, Vec<IpAddr>>,
+            fallback: Box<Config>,
+        },
+        /// A DNS-over-HTTPS server, whose addresses are looked up with the system resolver if they
+        /// aren't given.
+        DnsOverHttps {
+            server_name: String,
+            port: u16,
+            ips: Option<Vec<IpAddr>>,
+        },
+        Cached(Box<Config>, CacheConfig),
+        IpVersion(Box<Config>, IpVersion),
+    }
+
+    /// A restriction on or preference for the address families of upstreams.
+    #[derive(Clone, Copy)]
+    pub(crate) enum IpVersion {
+        V4Only,
+        V6Only,
+        /// Try IPv6 addresses before IPv4 ones.
+        PreferV6,
+    }
+
+    impl IpVersion {
+        fn apply(self, addresses: &mut Vec<IpAddr>) {
+            match self {
+                Self::V4Only => addresses.retain(IpAddr::is_ipv4),
+                Self::V6Only => addresses.retain(IpAddr::is_ipv6),
+                Self::PreferV6 => addresses.sort_by_key(IpAddr::is_ipv4),
+            }
+        }
     }
 
     #[derive(Clone)]
     pub(super) enum Resolver {
         System,
         TrustDns(Arc<trust_dns_resolver::TokioAsyncResolver>),
+        Chain(Arc<[Resolver]>),
+        Hosts(Arc<(HashMap<String, Vec<IpAddr>>, Resolver)>),
+        Cached(Arc<Cache>),
+        IpVersion(Arc<(Resolver, IpVersion)>),
     }
 
     impl Resolver {
         pub(super) fn new(config: Config) -> anyhow::Result<Self> {
             Ok(match config {
                 Config::System => Self::System,
+                Config::ResolvConf => {
+                    let (config, options) = trust_dns_resolver::system_conf::read_system_conf()
+                        .context("failed to read system DNS configuration")?;
+                    Self::trust_dns(config, options)?
+                }
                 Config::TrustDns(config) => {
-                    let resolver = trust_dns_resolver::AsyncResolver::tokio(
-                        config,
-                        trust_dns_resolver::config::ResolverOpts::default(),
-                    )
-                    .context("failed to create DNS resolver")?;
-                    Self::TrustDns(Arc::new(resolver))
+                    Self::trust_dns(config, trust_dns_resolver::config::ResolverOpts::default())?
+                }
+                Config::Chain(configs) => Self::Chain(
+                    configs
+                        .into_iter()
+                        .map(Self::new)
+                        .collect::<anyhow::Result<_>>()?,
+                ),
+                Config::Hosts { hosts, fallback } => {
+                    // Hosts are matched case-insensitively and without a trailing dot, like the
+                    // request hosts they are compared against.
+                    let hosts = hosts
+                        .into_iter()
+                        .map(|(host, ips)| {
+                            let host = host.trim_end_matches('.').to_ascii_lowercase();
+                            (host, ips)
+                        })
+                        .collect();
+                    Self::Hosts(Arc::new((hosts, Self::new(*fallback)?)))
+                }
+                Config::DnsOverHttps {
+                    server_name,
+                    port,
+                    ips,
+                } => {
+                    use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig};
+
+                    let ips = match ips {
+                        Some(ips) => ips,
+                        None => lookup_doh_server(&server_name, port)?,
+                    };
+                    let name_servers =
+                        NameServerConfigGroup::from_ips_https(&ips, port, server_name, true);
+                    let config = ResolverConfig::from_parts(None, Vec::new(), name_servers);
+                    Self::trust_dns(config, trust_dns_resolver::config::ResolverOpts::default())?
+                }
+                Config::Cached(config, cache) => Self::Cached(Arc::new(Cache {
+                    config: cache,
+                    resolver: Self::new(*config)?,
+                    entries: Mutex::new(HashMap::new()),
+                })),
+                Config::IpVersion(config, ip_version) => {
+                    Self::IpVersion(Arc::new((Self::new(*config)?, ip_version)))
                 }
             })
         }
+
+        fn trust_dns(
+            config: trust_dns_resolver::config::ResolverConfig,
+            mut options: trust_dns_resolver::config::ResolverOpts,
+        ) -> anyhow::Result<Self> {
+            // Both address families are looked up at once, and a failure of one doesn't prevent
+            // using the addresses from the other. Any restriction to one family is applied later.
+            options.ip_strategy = trust_dns_resolver::config::LookupIpStrategy::Ipv4AndIpv6;
+            let resolver = trust_dns_resolver::AsyncResolver::tokio(config, options)
+                .context("failed to create DNS resolver")?;
+            Ok(Self::TrustDns(Arc::new(resolver)))
+        }
     }
 
     impl Resolver {
-        pub(super) async fn resolve<'a>(
-            &self,
+        pub(super) async fn resolve(&self, host: &str) -> Result<vec::IntoIter<IpAddr>, Error> {
+            Ok(self.lookup(host).await?.addresses.into_iter())
+        }
+
+        fn lookup<'a>(
+            &'a self,
             host: &'a str,
-        ) -> Result<impl Iterator<Item = IpAddr> + 'a, Error> {
-            enum Either<A, B> {
-                A(A),
-                B(B),
-            }
-
-            impl<Item, A: Iterator<Item = Item>, B: Iterator<Item = Item>> Iterator for Either<A, B> {
-                type Item = Item;
-                fn next(&mut self) -> Option<Self::Item> {
-                    match self {
-                        Self::A(a) => a.next(),
-                        Self::B(b) => b.next(),
+        ) -> Pin<Box<dyn Future<Output = Result<Lookup, Error>> + Send + 'a>> {
+            Box::pin(async move {
+                match self {
+                    // The port is required but unused, since only the IP addresses are kept.
+                    Self::System => Ok(Lookup {
+                        addresses: net::lookup_host((host, 0))
+                            .await
+                            .map_err(Error::System)?
+                            .map(|addr| addr.ip())
+                            .collect(),
+                        valid_until: None,
+                    }),
+                    Self::TrustDns(resolver) => lookup_trust_dns(resolver, host).await,
+                    Self::Chain(resolvers) => lookup_chain(resolvers, host).await,
+                    Self::Hosts(hosts) => {
+                        let (hosts, fallback) = &**hosts;
+                        // Uses the fixed addresses of the host if it has any.
+                        match hosts.get(&*host.to_ascii_lowercase()) {
+                            Some(addresses) => Ok(Lookup {
+                                addresses: addresses.clone(),
+                                valid_until: None,
+                            }),
+                            None => fallback.lookup(host).await,
+                        }
                     }
+                    Self::Cached(cache) => cache.lookup(host).await,
+                    Self::IpVersion(inner) => {
+                        let (resolver, ip_version) = &**inner;
+                        let mut lookup = resolver.lookup(host).await?;
+                        let found = !lookup.addresses.is_empty();
+                        ip_version.apply(&mut lookup.addresses);
+                        if found && lookup.addresses.is_empty() {
+                            return Err(Error::WrongIpVersion);
+                        }
+                        Ok(lookup)
+                    }
+                }
+            })
+        }
+    }
+
+    /// Looks up a DNS-over-HTTPS server once at startup, since it can't be looked up over itself.
+    fn lookup_doh_server(host: &str, port: u16) -> anyhow::Result<Vec<IpAddr>> {
+        let ips = (host, port)
+            .to_socket_addrs()
+            .with_context(|| format!("failed to look up DNS-over-HTTPS server {host}"))?
+            .map(|addr| addr.ip())
+            .collect::<Vec<_>>();
+        anyhow::ensure!(
+            !ips.is_empty(),
+            "no addresses for DNS-over-HTTPS server {host}"
+        );
+        Ok(ips)
+    }
+
+    struct Lookup {
+        addresses: Vec<IpAddr>,
+        /// When the addresses expire, if the resolver knows.
+        valid_until: Option<Instant>,
+    }
+
+    /// Tries each resolver in turn, returning the results of the first one that succeeds.
+    async fn lookup_chain(resolvers: &[Resolver], host: &str) -> Result<Lookup, Error> {
+        let mut last_error = None;
+        for resolver in resolvers {
+            match resolver.lookup(host).await {
+                Ok(lookup) => return Ok(lookup),
+                Err(e) => {
+                    log::debug!("resolver in chain failed to resolve {host}: {e:?}");
+                    last_error = Some(e);
                 }
             }
+        }
+        Err(last_error.unwrap_or(Error::EmptyChain))
+    }
 
-            Ok(match self {
-                Self::System => Either::A(
-                    net::lookup_host(host)
-                        .await
-                        .map_err(Error::System)?
-                        .map(|addr| addr.ip()),
-                ),
-                Self::TrustDns(resolver) => Either::B(
-                    resolver
-                        .lookup_ip(host)
-                        .await
-                        .map_err(Error::TrustDns)?
-                        .into_iter(),
-                ),
-            })
+    /// Looks up a host with trust-dns, which also takes care of the hosts file and search domains.
+    async fn lookup_trust_dns(
+        resolver: &trust_dns_resolver::TokioAsyncResolver,
+        host: &str,
+    ) -> Result<Lookup, Error> {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Lookup {
+                addresses: vec![ip],
+                valid_until: None,
+            });
+        }
+
+        let lookup = resolver.lookup_ip(host).await.map_err(Error::TrustDns)?;
+        Ok(Lookup {
+            valid_until: Some(lookup.valid_until()),
+            addresses: lookup.iter().collect(),
+        })
+    }
+
+    pub(crate) struct CacheConfig {
+        pub(crate) capacity: usize,
+        /// How long to cache addresses for when the resolver doesn't give a TTL.
+        pub(crate) ttl: Duration,
+        /// How long to remember that a name doesn't exist.
+        pub(crate) negative_ttl: Duration,
+    }
+
+    /// A cache of lookups in front of another resolver.
+    pub(super) struct Cache {
+        config: CacheConfig,
+        resolver: Resolver,
+        entries: Mutex<HashMap<String, CacheEntry>>,
+    }
+
+    struct CacheEntry {
+        result: Result<Vec<IpAddr>, trust_dns_resolver::error::ResolveError>,
+        expires: Instant,
+    }
+
+    impl Cache {
+        async fn lookup(&self, host: &str) -> Result<Lookup, Error> {
+            let host = &*host.to_ascii_lowercase();
+            let now = Instant::now();
+            if let Some(entry) = self.entries.lock().unwrap().get(host) {
+                if entry.expires > now {
+                    return match &entry.result {
+                        Ok(addresses) => Ok(Lookup {
+                            addresses: addresses.clone(),
+                            valid_until: Some(entry.expires),
+                        }),
+                        Err(e) => Err(Error::TrustDns(e.clone())),
+                    };
+                }
+            }
+
+            let result = self.resolver.lookup(host).await;
+            let entry = match &result {
+                Ok(lookup) => CacheEntry {
+                    result: Ok(lookup.addresses.clone()),
+                    expires: lookup.valid_until.unwrap_or(now + self.config.ttl),
+                },
+                // Only names that definitely don't exist are cached, not transient failures.
+                Err(Error::TrustDns(e))
+                    if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) =>
+                {
+                    CacheEntry {
+                        result: Err(e.clone()),
+                        expires: now + self.config.negative_ttl,
+                    }
+                }
+                Err(_) => return result,
+            };
+
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= self.config.capacity && !entries.contains_key(host) {
+                entries.retain(|_, entry| entry.expires > now);
+            }
+            if entries.len() >= self.config.capacity && !entries.contains_key(host) {
+                let soonest = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(host, _)| host.clone());
+                if let Some(soonest) = soonest {
+                    entries.remove(&soonest);
+                }
+            }
+            if self.config.capacity != 0 {
+                entries.insert(host.to_owned(), entry);
+            }
+            drop(entries);
+            result
+        }
+    }
+
+    /// Removes addresses that aren't publicly routable, so that requests can't be made to reach
+    /// internal services. Fails if no addresses remain.
+    pub(super) fn public_only(
+        host: &str,
+        addresses: impl Iterator<Item = IpAddr>,
+    ) -> Result<Vec<IpAddr>, Error> {
+        let (private, public): (Vec<_>, Vec<_>) = addresses.partition(|&ip| is_private(ip));
+        if !private.is_empty() {
+            log::debug!("ignoring private addresses of {host}: {private:?}");
+        }
+        if public.is_empty() && !private.is_empty() {
+            return Err(Error::Private);
+        }
+        Ok(public)
+    }
+
+    pub(super) fn is_private(ip: IpAddr) -> bool {
+        match ip {
+            IpAddr::V4(ip) => is_private_v4(ip),
+            IpAddr::V6(ip) => {
+                if let Some(ip) = ip.to_ipv4_mapped() {
+                    return is_private_v4(ip);
+                }
+                let first = ip.segments()[0];
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    // Unique local addresses, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // Link-local addresses, fe80::/10
+                    || first & 0xffc0 == 0xfe80
+            }
         }
     }
 
+    fn is_private_v4(ip: Ipv4Addr) -> bool {
+        let [a, b, ..] = ip.octets();
+        ip.is_private()
+            || ip.is_loopback()
+            // Also covers the cloud metadata address, 169.254.169.254.
+            || ip.is_link_local()
+            || ip.is_unspecified()
+            || ip.is_broadcast()
+            // Shared address space, 100.64.0.0/10
+            || (a == 100 && b & 0xc0 == 64)
+    }
+
     #[derive(Debug)]
     pub(super) enum Error {
         System(io::Error),
         TrustDns(trust_dns_resolver::error::ResolveError),
+        EmptyChain,
+        Private,
+        WrongIpVersion,
     }
 
     impl Display for Error {
         fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-            f.write_str("failed to resolve DNS name")
+            match self {
+                Self::EmptyChain => f.write_str("no DNS resolvers configured"),
+                Self::Private => f.write_str("DNS name only resolved to private addresses"),
+                Self::WrongIpVersion => {
+                    f.write_str("DNS name only resolved to addresses of a disabled IP version")
+                }
+                _ => f.write_str("failed to resolve DNS name"),
+            }
         }
     }
 
     impl StdError for Error {
         fn source(&self) -> Option<&(dyn StdError + 'static)> {
-            Some(match self {
-                Self::System(e) => e,
-                Self::TrustDns(e) => e,
-            })
+            match self {
+                Self::System(e) => Some(e),
+                Self::TrustDns(e) => Some(e),
+                Self::EmptyChain | Self::Private | Self::WrongIpVersion => None,
+            }
+        }
+    }
+
+    #[test]
+    fn private_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(is_private(ip.parse().unwrap()), "{ip} is private");
+        }
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(!is_private(ip.parse().unwrap()), "{ip} is public");
         }
     }
+
+    #[test]
+    fn filtering_private_addresses() {
+        let ips = |ips: &[&str]| ips.iter().map(|ip| ip.parse().unwrap()).collect::<Vec<_>>();
+
+        let mixed = public_only("host", ips(&["10.0.0.1", "1.1.1.1"]).into_iter()).unwrap();
+        assert_eq!(mixed, ips(&["1.1.1.1"]));
+
+        let private = public_only("host", ips(&["127.0.0.1", "::1"]).into_iter());
+        assert!(matches!(private, Err(Error::Private)));
+    }
+
+    #[tokio::test]
+    async fn host_overrides() {
+        let pinned = vec![
+            "203.0.113.7".parse().unwrap(),
+            "2001:db8::7".parse().unwrap(),
+        ];
+        let resolver = Resolver::new(Config::Hosts {
+            hosts: HashMap::from([("Pinned.Example.".to_owned(), pinned.clone())]),
+            fallback: Box::new(Config::Chain(Vec::new())),
+        })
+        .unwrap();
+
+        let resolved = resolver.resolve("pinned.example").await.unwrap();
+        assert_eq!(resolved.collect::<Vec<_>>(), pinned);
+
+        let fallen_back = resolver.resolve("other.example").await;
+        assert!(matches!(fallen_back, Err(Error::EmptyChain)));
+    }
+
+    #[test]
+    fn ip_versions() {
+        let v4 = IpAddr::from([203, 0, 113, 7]);
+        let v6 = "2001:db8::7".parse().unwrap();
+        let apply = |ip_version: IpVersion| {
+            let mut addresses = vec![v4, v6];
+            ip_version.apply(&mut addresses);
+            addresses
+        };
+        assert_eq!(apply(IpVersion::V4Only), [v4]);
+        assert_eq!(apply(IpVersion::V6Only), [v6]);
+        assert_eq!(apply(IpVersion::PreferV6), [v6, v4]);
+    }
+
+    #[tokio::test]
+    async fn restricting_ip_version() {
+        let resolver = Resolver::new(Config::IpVersion(
+            Box::new(Config::Hosts {
+                hosts: HashMap::from([("v4.example".to_owned(), vec![[203, 0, 113, 7].into()])]),
+                fallback: Box::new(Config::Chain(Vec::new())),
+            }),
+            IpVersion::V6Only,
+        ))
+        .unwrap();
+        let resolved = resolver.resolve("v4.example").await;
+        assert!(matches!(resolved, Err(Error::WrongIpVersion)));
+    }
+
+    #[tokio::test]
+    async fn caching() {
+        let addresses = |last| vec![IpAddr::from([203, 0, 113, last])];
+        let resolver = Resolver::new(Config::Cached(
+            Box::new(Config::Hosts {
+                hosts: HashMap::from([
+                    ("a.example".to_owned(), addresses(1)),
+                    ("b.example".to_owned(), addresses(2)),
+                ]),
+                fallback: Box::new(Config::Chain(Vec::new())),
+            }),
+            CacheConfig {
+                capacity: 1,
+                ttl: Duration::from_secs(60),
+                negative_ttl: Duration::from_secs(5),
+            },
+        ))
+        .unwrap();
+        let Resolver::Cached(cache) = &resolver else {
+            unreachable!()
+        };
+        let resolver = &resolver;
+        let resolve =
+            |host| async move { resolver.resolve(host).await.unwrap().collect::<Vec<_>>() };
+
+        assert_eq!(resolve("A.example").await, addresses(1));
+        // Cached addresses are used until they expire.
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut("a.example")
+            .unwrap()
+            .result = Ok(addresses(9));
+        assert_eq!(resolve("a.example").await, addresses(9));
+        cache
+            .entries
+            .lock()
+            .unwrap()
+            .get_mut("a.example")
+            .unwrap()
+            .expires = Instant::now();
+        assert_eq!(resolve("a.example").await, addresses(1));
+
+        // The cache is full, so `a.example` is evicted.
+        assert_eq!(resolve("b.example").await, addresses(2));
+        let hosts = || {
+            cache
+                .entries
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(hosts(), ["b.example"]);
+
+        // Failures other than a name not existing aren't cached.
+        assert!(resolver.resolve("c.example").await.is_err());
+        assert_eq!(hosts(), ["b.example"]);
+    }
 }
 use resolver::Resolver;
+
+#[test]
+fn stripping_domain() {
+    assert_eq!(
+        upstream_host("www.rust-lang.org.example.com", "example.com"),
+        Some("www.rust-lang.org")
+    );
+    assert_eq!(
+        upstream_host("docs.rs.EXAMPLE.com", "example.com"),
+        Some("docs.rs")
+    );
+    assert_eq!(upstream_host("example.com", "example.com"), None);
+    assert_eq!(upstream_host(".example.com", "example.com"), None);
+    assert_eq!(upstream_host("notexample.com", "example.com"), None);
+    assert_eq!(upstream_host("www.rust-lang.org", "example.com"), None);
+}
+
+#[test]
+fn merging_headers() {
+    let mut headers = HeaderMap::new();
+    headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static("gzip"));
+    headers.append(header::ACCEPT_ENCODING, HeaderValue::from_static("br"));
+    headers.append(header::ACCEPT, HeaderValue::from_static("text/html"));
+    headers.append(header::ACCEPT, HeaderValue::from_static("*/*"));
+    headers.append(header::COOKIE, HeaderValue::from_static("a=b"));
+
+    merge_headers(
+        &mut headers,
+        &[header::ACCEPT_ENCODING, header::COOKIE, header::USER_AGENT],
+    );
+
+    let values = |name| headers.get_all(name).iter().collect::<Vec<_>>();
+    assert_eq!(values(header::ACCEPT_ENCODING), ["gzip, br"]);
+    assert_eq!(values(header::ACCEPT), ["text/html", "*/*"]);
+    assert_eq!(values(header::COOKIE), ["a=b"]);
+    assert!(values(header::USER_AGENT).is_empty());
+}
+
+#[test]
+fn stripping_hop_by_hop_headers() {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONNECTION,
+        HeaderValue::from_static("X-Custom, close"),
+    );
+    headers.insert("x-custom", HeaderValue::from_static("1"));
+    headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+    headers.insert(header::TE, HeaderValue::from_static("gzip"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static("h2c"));
+    headers.insert(header::ACCEPT, HeaderValue::from_static("*/*"));
+
+    strip_hop_by_hop(&mut headers, false);
+
+    assert_eq!(headers.len(), 1);
+    assert_eq!(headers[header::ACCEPT], "*/*");
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::TE, HeaderValue::from_static("trailers"));
+    strip_hop_by_hop(&mut headers, false);
+    assert_eq!(headers[header::TE], "trailers");
+
+    // Clients can't use `Connection` to strip the headers the proxy adds itself.
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONNECTION,
+        HeaderValue::from_static("X-Forwarded-For, X-Forwarded-Proto"),
+    );
+    let peer = Some(Peer {
+        addr: Some("203.0.113.7:50000".parse().unwrap()),
+        https: true,
+    });
+    strip_hop_by_hop(&mut headers, false);
+    forward_headers(&mut headers, peer, None, Forwarding::default());
+    assert!(!headers.contains_key(header::CONNECTION));
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+}
+
+#[test]
+fn forwarding_headers() {
+    let peer = Some(Peer {
+        addr: Some("203.0.113.7:50000".parse().unwrap()),
+        https: true,
+    });
+    let spoofed = || {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.1"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+        headers.insert(
+            header::FORWARDED,
+            HeaderValue::from_static("for=198.51.100.1"),
+        );
+        headers
+    };
+    // The host is passed on as the client sent it, not in canonical form.
+    let host = || Some(HeaderValue::from_static("Docs.RS.example.com.:8443"));
+    let untrusted = Forwarding::default();
+    let trusted = Forwarding {
+        trusted: true,
+        ..Forwarding::default()
+    };
+
+    let mut headers = spoofed();
+    forward_headers(&mut headers, peer, host(), untrusted);
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+    assert_eq!(headers["x-forwarded-proto"], "https");
+    assert_eq!(headers["x-forwarded-host"], "Docs.RS.example.com.:8443");
+    assert!(!headers.contains_key(header::FORWARDED));
+
+    let mut headers = spoofed();
+    forward_headers(&mut headers, peer, host(), trusted);
+    assert_eq!(headers["x-forwarded-for"], "198.51.100.1, 203.0.113.7");
+    assert_eq!(headers["x-forwarded-proto"], "http");
+    assert_eq!(headers["x-forwarded-host"], "Docs.RS.example.com.:8443");
+    assert!(headers.contains_key(header::FORWARDED));
+
+    // Clients over Unix sockets have no address to add.
+    let unix = Some(Peer {
+        addr: None,
+        https: false,
+    });
+    let mut headers = spoofed();
+    forward_headers(&mut headers, unix, host(), untrusted);
+    assert!(!headers.contains_key("x-forwarded-for"));
+    assert_eq!(headers["x-forwarded-proto"], "http");
+
+    let mut headers = spoofed();
+    forward_headers(&mut headers, unix, host(), trusted);
+    assert_eq!(headers["x-forwarded-for"], "198.51.100.1");
+
+    let replace = Forwarding {
+        replace_chain: true,
+        ..trusted
+    };
+    let mut headers = spoofed();
+    forward_headers(&mut headers, peer, host(), replace);
+    assert_eq!(headers["x-forwarded-for"], "203.0.113.7");
+    assert_eq!(headers["x-forwarded-proto"], "http");
+
+    let capped = Forwarding {
+        max_chain: Some(2),
+        ..trusted
+    };
+    let mut headers = spoofed();
+    headers.append(
+        "x-forwarded-for",
+        HeaderValue::from_static("198.51.100.2, 198.51.100.3"),
+    );
+    forward_headers(&mut headers, peer, host(), capped);
+    assert_eq!(headers["x-forwarded-for"], "198.51.100.3, 203.0.113.7");
+
+    let req = http::Request::builder()
+        .header(header::HOST, "Docs.RS.example.com.:8443")
+        .body(hyper::Body::empty())
+        .unwrap();
+    assert_eq!(original_host(&req), host());
+    let req = http::Request::builder()
+        .uri("https://Docs.RS.example.com.:8443/")
+        .body(hyper::Body::empty())
+        .unwrap();
+    assert_eq!(original_host(&req), host());
+}
+
+#[tokio::test]
+async fn rate_limiting_clients_with_addresses() {
+    let proxy = Proxy::new(Config {
+        rate_limit: Some(rate_limit::Config {
+            requests_per_second: 1.0,
+            burst: 1,
+        }),
+        ..test_config()
+    })
+    .unwrap();
+    let peer = |addr: Option<&str>| {
+        Some(Peer {
+            addr: addr.map(|addr| addr.parse().unwrap()),
+            https: false,
+        })
+    };
+
+    let client = peer(Some("203.0.113.7:50000"));
+    assert!(rate_limited(&proxy.inner, client).is_none());
+    let response = rate_limited(&proxy.inner, client).unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    for _ in 0..2 {
+        assert!(rate_limited(&proxy.inner, peer(None)).is_none());
+    }
+}
+
+#[test]
+fn allowed_domains() {
+    let allowed = ["rust-lang.org".to_owned(), "docs.rs".to_owned()];
+    assert!(is_allowed("www.rust-lang.org", &allowed));
+    assert!(is_allowed("rust-lang.org", &allowed));
+    assert!(is_allowed("docs.rs", &allowed));
+    assert!(!is_allowed("notrust-lang.org", &allowed));
+    assert!(!is_allowed("rust-lang.org.evil.com", &allowed));
+    assert!(is_allowed("anything.com", &[]));
+}
+
+#[test]
+fn canonical_request_host() {
+    let host = |host| {
+        let req = http::Request::builder()
+            .header(header::HOST, host)
+            .body(hyper::Body::empty())
+            .unwrap();
+        request_host(&req)
+    };
+    assert_eq!(
+        host("WWW.Rust-Lang.org.example.com").as_deref(),
+        Some("www.rust-lang.org.example.com")
+    );
+    assert_eq!(
+        host("www.rust-lang.org.example.com:443").as_deref(),
+        Some("www.rust-lang.org.example.com")
+    );
+    assert_eq!(
+        host("www.rust-lang.org.example.com:80").as_deref(),
+        Some("www.rust-lang.org.example.com")
+    );
+    assert_eq!(
+        host("docs.rs.example.com.").as_deref(),
+        Some("docs.rs.example.com")
+    );
+    assert_eq!(host("not a host"), None);
+
+    let req = http::Request::builder()
+        .uri("https://Docs.RS.example.com:443/")
+        .body(hyper::Body::empty())
+        .unwrap();
+    assert_eq!(request_host(&req).as_deref(), Some("docs.rs.example.com"));
+}
+
+#[test]
+fn normalizing_paths() {
+    for (path, normalized) in [
+        ("/", "/"),
+        ("/crates/spx", "/crates/spx"),
+        ("/crates/", "/crates/"),
+        ("//foo/../bar", "/bar"),
+        ("/a//b///c", "/a/b/c"),
+        ("/a/./b/.", "/a/b/"),
+        ("/a/b/..", "/a/"),
+        ("/../../etc/passwd", "/etc/passwd"),
+        ("/a/%2E%2e/b", "/b"),
+        ("/..", "/"),
+    ] {
+        assert_eq!(normalize_path(path), normalized, "{path}");
+    }
+
+    let mut req = http::Request::builder()
+        .uri("//admin/../crates/./spx?q=a//b")
+        .body(hyper::Body::empty())
+        .unwrap();
+    rewrite_request(&mut req, "crates.io", true).unwrap();
+    assert_eq!(req.uri(), "https://crates.io/crates/spx?q=a//b");
+
+    let mut req = http::Request::builder()
+        .uri("//admin/../crates")
+        .body(hyper::Body::empty())
+        .unwrap();
+    rewrite_request(&mut req, "crates.io", false).unwrap();
+    assert_eq!(req.uri(), "https://crates.io//admin/../crates");
+}
+
+#[test]
+fn proxying_urls() {
+    let proxied = |url| proxied_url(url, "example.com");
+    assert_eq!(
+        proxied("https://static.rust-lang.org/foo?bar").as_deref(),
+        Some("https://static.rust-lang.org.example.com/foo?bar")
+    );
+    assert_eq!(
+        proxied("http://docs.rs").as_deref(),
+        Some("http://docs.rs.example.com/")
+    );
+    assert_eq!(proxied("/relative/path"), None);
+    assert_eq!(proxied("relative"), None);
+    assert_eq!(proxied("https://docs.rs:8443/"), None);
+    assert_eq!(proxied("ftp://docs.rs/"), None);
+    assert_eq!(proxied("https://exa mple.com/"), None);
+}
+
+#[test]
+fn unproxying_urls() {
+    let upstream = |url| upstream_url(url, "example.com");
+    assert_eq!(
+        upstream("http://docs.rs.example.com/spx?search=1").as_deref(),
+        Some("https://docs.rs/spx?search=1")
+    );
+    assert_eq!(
+        upstream("https://www.rust-lang.org.example.com:8443").as_deref(),
+        Some("https://www.rust-lang.org/")
+    );
+    assert_eq!(upstream("https://example.com/"), None);
+    assert_eq!(upstream("https://docs.rs/"), None);
+    assert_eq!(upstream("/relative"), None);
+}
+
+#[test]
+fn proxying_csp() {
+    let proxied = |policy| proxied_csp(policy, "example.com");
+    assert_eq!(
+        proxied(
+            "default-src 'self'; script-src 'self' https://*.rust-lang.org cdn.example.org/js/"
+        ),
+        "default-src 'self'; script-src 'self' https://*.rust-lang.org.example.com \
+            cdn.example.org.example.com/js/"
+    );
+    assert_eq!(
+        proxied("img-src data: * https://docs.rs:8443; report-uri /csp; sandbox allow-forms"),
+        "img-src data: * https://docs.rs:8443; report-uri /csp; sandbox allow-forms"
+    );
+    assert_eq!(
+        proxied("style-src 'nonce-abc' static.rust-lang.org;"),
+        "style-src 'nonce-abc' static.rust-lang.org.example.com"
+    );
+}
+
+#[test]
+fn proxying_cookies() {
+    let proxied = |cookie| proxied_cookie(cookie, "example.com");
+    assert_eq!(
+        proxied("id=a3fWa; Domain=.rust-lang.org; Path=/; Secure; HttpOnly; SameSite=Lax")
+            .as_deref(),
+        Some("id=a3fWa; Domain=rust-lang.org.example.com; Path=/; Secure; HttpOnly; SameSite=Lax")
+    );
+    assert_eq!(
+        proxied("id=a; domain=docs.rs").as_deref(),
+        Some("id=a; domain=docs.rs.example.com")
+    );
+    assert_eq!(proxied("id=a; Path=/"), None);
+    assert_eq!(proxied("domain=a; Path=/"), None);
+}
+
+#[test]
+fn variants_are_stable() {
+    let variants = Variants {
+        upstreams: vec!["a.example.org".to_owned(), "b.example.org".to_owned()],
+        key: VariantKey::Cookie("session".to_owned()),
+    };
+    let request = |cookie: &str| {
+        http::Request::builder()
+            .header(header::COOKIE, cookie)
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+
+    assert_eq!(
+        choose_variant(&variants, &request("other=1")),
+        Some("a.example.org")
+    );
+    for session in 0..20 {
+        let cookie = format!("theme=dark; session={session}");
+        let first = choose_variant(&variants, &request(&cookie));
+        let second = choose_variant(&variants, &request(&format!("session={session}")));
+        assert_eq!(first, second);
+    }
+
+    assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a(b"foobar"), 0x8594_4171_f739_67e8);
+
+    let variants = Variants {
+        upstreams: vec![
+            "a.example.org".to_owned(),
+            "b.example.org".to_owned(),
+            "c.example.org".to_owned(),
+        ],
+        key: VariantKey::Header(HeaderName::from_static("x-user")),
+    };
+    for (user, upstream) in [
+        ("alice", "c.example.org"),
+        ("bob", "a.example.org"),
+        ("carol", "b.example.org"),
+        ("dave", "a.example.org"),
+    ] {
+        let req = http::Request::builder()
+            .header("x-user", user)
+            .body(hyper::Body::empty())
+            .unwrap();
+        assert_eq!(choose_variant(&variants, &req), Some(upstream), "{user}");
+    }
+}
+
+#[tokio::test]
+async fn upstream_request_has_bare_host() {
+    let (client_io, server_io) = tokio::io::duplex(4096);
+
+    let server = tokio::spawn(async move {
+        let (host_sender, host_receiver) = tokio::sync::oneshot::channel();
+        let mut host_sender = Some(host_sender);
+        let service = hyper::service::service_fn(move |req: http::Request<hyper::Body>| {
+            let host = req.headers().get(header::HOST).cloned();
+            host_sender.take().unwrap().send(host).unwrap();
+            async { Ok::<_, Infallible>(http::Response::new(hyper::Body::empty())) }
+        });
+        hyper::server::conn::Http::new()
+            .serve_connection(server_io, service)
+            .await
+            .unwrap();
+        host_receiver.await.unwrap()
+    });
+
+    let mut req = http::Request::builder()
+        .uri("/crates?q=spx")
+        .header(header::HOST, "www.rust-lang.org.example.com")
+        .body(hyper::Body::empty())
+        .unwrap();
+    rewrite_request(&mut req, "www.rust-lang.org", false).unwrap();
+    assert_eq!(req.uri(), "https://www.rust-lang.org/crates?q=spx");
+
+    let (mut sender, connection) = hyper::client::conn::handshake(client_io).await.unwrap();
+    tokio::spawn(connection);
+    sender.send_request(req).await.unwrap();
+    drop(sender);
+
+    assert_eq!(server.await.unwrap().unwrap(), "www.rust-lang.org");
+}
+
+#[tokio::test]
+async fn proxying_websockets() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    // An upstream that accepts the handshake and then echoes everything back.
+    let (proxy_io, upstream_io) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let service = hyper::service::service_fn(|mut req: http::Request<hyper::Body>| {
+            assert_eq!(req.headers()[header::UPGRADE], "websocket");
+            let upgrade = hyper::upgrade::on(&mut req);
+            tokio::spawn(async move {
+                let upgraded = upgrade.await.unwrap();
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+            let response = http::Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "websocket")
+                .header(header::SEC_WEBSOCKET_ACCEPT, "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                .body(hyper::Body::empty())
+                .unwrap();
+            async { Ok::<_, Infallible>(response) }
+        });
+        hyper::server::conn::Http::new()
+            .serve_connection(upstream_io, service)
+            .with_upgrades()
+            .await
+            .unwrap();
+    });
+
+    // The proxy, handling the upgrade the same way as `handle` does.
+    let (mut upstream, connection) = hyper::client::conn::handshake(proxy_io).await.unwrap();
+    tokio::spawn(connection);
+    let (client_io, proxy_io) = tokio::io::duplex(4096);
+    tokio::spawn(async move {
+        let service = hyper::service::service_fn(move |mut req: http::Request<hyper::Body>| {
+            let upgrade = is_websocket_upgrade(&req).then(|| hyper::upgrade::on(&mut req));
+            strip_hop_by_hop(req.headers_mut(), upgrade.is_some());
+            let response = upstream.send_request(req);
+            async move {
+                let mut response = response.await.unwrap();
+                rewrite_response(&mut response, "example.com", Csp::Keep);
+                tunnel(upgrade.unwrap(), &mut response);
+                Ok::<_, Infallible>(response)
+            }
+        });
+        hyper::server::conn::Http::new()
+            .serve_connection(proxy_io, service)
+            .with_upgrades()
+            .await
+            .unwrap();
+    });
+
+    let (mut proxy, connection) = hyper::client::conn::handshake(client_io).await.unwrap();
+    tokio::spawn(connection);
+    let req = http::Request::builder()
+        .uri("/chat")
+        .header(header::HOST, "ws.example.org.example.com")
+        .header(header::CONNECTION, "keep-alive, Upgrade")
+        .header(header::UPGRADE, "websocket")
+        .header(header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+        .header(header::SEC_WEBSOCKET_VERSION, "13")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let response = proxy.send_request(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    assert_eq!(response.headers()[header::UPGRADE], "websocket");
+
+    let mut upgraded = hyper::upgrade::on(response).await.unwrap();
+    upgraded.write_all(b"hello").await.unwrap();
+    let mut echoed = [0; 5];
+    upgraded.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello");
+}
+
+#[tokio::test]
+async fn rewriting_compressed_html() {
+    use std::io::Write as _;
+
+    let html = br#"<a href="https://www.rust-lang.org/learn">Learn</a>"#;
+    let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gzip.write_all(html).unwrap();
+    let gzip = gzip.finish().unwrap();
+    let response = |max_decompressed| {
+        let response = http::Response::builder()
+            .header(header::CONTENT_TYPE, "text/html; charset=utf-8")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(hyper::Body::from(gzip.clone()))
+            .unwrap();
+        assert!(is_rewritable_html(&response));
+        rewrite_html(response, "example.com", max_decompressed)
+    };
+
+    let rewritten = response(1024).await;
+    assert!(!rewritten.headers().contains_key(header::CONTENT_ENCODING));
+    let body = hyper::body::to_bytes(rewritten.into_body()).await.unwrap();
+    assert_eq!(
+        body,
+        r#"<a href="https://www.rust-lang.org.example.com/learn">Learn</a>"#
+    );
+
+    // Pages that decompress to too much could be decompression bombs.
+    let too_large = response(16).await;
+    assert_eq!(too_large.status(), StatusCode::BAD_GATEWAY);
+}
+
+#[tokio::test]
+async fn cache_status_header() {
+    let cache = |status_header| {
+        Arc::new(Cache::new(cache::Config {
+            max_bytes: 1024,
+            max_entry_bytes: 1024,
+            status_header,
+        }))
+    };
+    let request = |method| {
+        http::Request::builder()
+            .method(method)
+            .uri("/")
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+    let upstream = || {
+        http::Response::builder()
+            .header(header::CACHE_CONTROL, "max-age=60")
+            .body(hyper::Body::from("cached"))
+            .unwrap()
+    };
+
+    let with_status = cache(true);
+    let key = Cache::key(&request(http::Method::POST), "www.rust-lang.org");
+    let response = fill_cache(&with_status, key, upstream());
+    assert_eq!(response.headers()[X_CACHE], "BYPASS");
+
+    let key = Cache::key(&request(http::Method::GET), "www.rust-lang.org");
+    assert!(cached_response(Some(&with_status), key.as_deref()).is_none());
+    let response = fill_cache(&with_status, key.clone(), upstream());
+    assert_eq!(response.headers()[X_CACHE], "MISS");
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let response = cached_response(Some(&with_status), key.as_deref()).unwrap();
+    assert_eq!(response.headers()[X_CACHE], "HIT");
+
+    let without_status = cache(false);
+    let response = fill_cache(&without_status, key.clone(), upstream());
+    assert!(!response.headers().contains_key(X_CACHE));
+    hyper::body::to_bytes(response.into_body()).await.unwrap();
+    let response = cached_response(Some(&without_status), key.as_deref()).unwrap();
+    assert!(!response.headers().contains_key(X_CACHE));
+}
+
+#[cfg(test)]
+pub(crate) fn test_config() -> Config {
+    Config {
+        domain: "example.com".to_owned(),
+        resolver: resolver::Config::System,
+        deny_user_agents: None,
+        upstream_tcp_user_timeout: None,
+        upstream_tcp_keepalive: None,
+        upstream_http2_prior_knowledge: false,
+        preserve_header_case: false,
+        no_sni_hosts: Vec::new(),
+        variants: HashMap::new(),
+        rewrite_html: false,
+        max_decompressed_html: 100 * 1024 * 1024,
+        csp: Csp::Keep,
+        allowed_domains: Vec::new(),
+        merge_headers: Vec::new(),
+        allow_private_ips: false,
+        own_ips: Vec::new(),
+        max_subdomain_labels: None,
+        health_path: "/healthz".to_owned(),
+        metrics_path: None,
+        rewrite_referer: false,
+        normalize_path: false,
+        access_log_format: access_log::Format::Human,
+        slow_request_threshold: None,
+        forwarding: Forwarding::default(),
+        load_shedding: None,
+        upstream_timeout: None,
+        upstream_time_header: false,
+        max_response_header_bytes: 256 * 1024,
+        hsts: None,
+        rate_limit: None,
+        cache: None,
+        compression: None,
+        responses: HashMap::new(),
+    }
+}
+
+#[test]
+fn response_header_size() {
+    let mut headers = HeaderMap::new();
+    assert_eq!(header_bytes(&headers), 0);
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/html"));
+    headers.append(header::SET_COOKIE, HeaderValue::from_static("a=1"));
+    headers.append(header::SET_COOKIE, HeaderValue::from_static("b=2"));
+    assert_eq!(
+        header_bytes(&headers),
+        "content-type: text/html\r\n".len() + 2 * "set-cookie: a=1\r\n".len()
+    );
+}
+
+#[test]
+fn conflicting_framing_is_detected() {
+    let headers = |pairs: &[(HeaderName, &'static str)]| {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(name, HeaderValue::from_static(value));
+        }
+        headers
+    };
+    let (length, encoding) = (header::CONTENT_LENGTH, header::TRANSFER_ENCODING);
+
+    assert_eq!(conflicting_framing(&headers(&[])), None);
+    assert_eq!(
+        conflicting_framing(&headers(&[(length.clone(), "5")])),
+        None
+    );
+    assert_eq!(
+        conflicting_framing(&headers(&[(encoding.clone(), "chunked")])),
+        None,
+    );
+    // Repeating the same length is allowed.
+    assert_eq!(
+        conflicting_framing(&headers(&[(length.clone(), "5"), (length.clone(), "5, 5")])),
+        None,
+    );
+    assert!(
+        conflicting_framing(&headers(&[(length.clone(), "5"), (length.clone(), "6")])).is_some()
+    );
+    assert!(conflicting_framing(&headers(&[(length.clone(), "5, 6")])).is_some());
+    assert!(conflicting_framing(&headers(&[(length, "5"), (encoding, "chunked")])).is_some());
+}
+
+#[tokio::test]
+async fn conflicting_request_framing_is_rejected() {
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    let proxy = Proxy::new(test_config()).unwrap();
+    let (mut client, server_io) = tokio::io::duplex(4096);
+    tokio::spawn(hyper::server::conn::Http::new().serve_connection(server_io, proxy));
+
+    // hyper ignores `Content-Length` after `Transfer-Encoding`, but not before it.
+    client
+        .write_all(
+            b"POST / HTTP/1.1\r\n\
+            Host: www.rust-lang.org.example.com\r\n\
+            Content-Length: 5\r\n\
+            Transfer-Encoding: chunked\r\n\
+            \r\n\
+            0\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = [0; 12];
+    time::timeout(Duration::from_secs(1), client.read_exact(&mut response))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&response, b"HTTP/1.1 400");
+}
+
+#[tokio::test]
+async fn custom_policy_responses() {
+    let body = r#"{"error":"forbidden"}"#;
+    let mut proxy = Proxy::new(Config {
+        deny_user_agents: Some(Regex::new("curl").unwrap()),
+        responses: HashMap::from([(
+            StatusCode::FORBIDDEN,
+            CustomResponse {
+                content_type: HeaderValue::from_static("application/json"),
+                body: Bytes::from_static(body.as_bytes()),
+            },
+        )]),
+        ..test_config()
+    })
+    .unwrap();
+    let req = http::Request::builder()
+        .header(header::HOST, "www.rust-lang.org.example.com")
+        .header(header::USER_AGENT, "curl/7.81.0")
+        .body(hyper::Body::empty())
+        .unwrap();
+    let response = proxy.call(req).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(bytes, body);
+}
+
+#[tokio::test]
+async fn self_referential_hosts_are_rejected() {
+    let mut proxy = Proxy::new(test_config()).unwrap();
+    for host in ["example.com.example.com", "www.EXAMPLE.com.example.com"] {
+        let req = http::Request::builder()
+            .header(header::HOST, host)
+            .body(hyper::Body::empty())
+            .unwrap();
+        let response = time::timeout(Duration::from_secs(1), proxy.call(req))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::MISDIRECTED_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn health_check() {
+    let mut proxy = Proxy::new(test_config()).unwrap();
+    let request = |host, path| {
+        http::Request::builder()
+            .uri(path)
+            .header(header::HOST, host)
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+
+    let response = proxy
+        .call(request("example.com", "/healthz"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = proxy
+        .call(request("EXAMPLE.com:443", "/healthz"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = proxy.call(request("example.com", "/")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn connector_error_responses() {
+    let connector = Connector {
+        resolver: Resolver::new(resolver::Config::System).unwrap(),
+        tcp_user_timeout: None,
+        tcp_keepalive: None,
+        allow_private_ips: true,
+        own_ips: Arc::from([]),
+    };
+    let client = hyper::Client::builder().build::<_, hyper::Body>(connector);
+
+    let e = client
+        .get(Uri::from_static("http://spx-test.invalid/"))
+        .await
+        .unwrap_err();
+    let response = upstream_error_response(&e);
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "failed to resolve upstream host\n");
+
+    // Find a port that nothing is listening on.
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+    drop(listener);
+
+    let uri = format!("http://127.0.0.1:{port}/").parse::<Uri>().unwrap();
+    let e = client.get(uri).await.unwrap_err();
+    let response = upstream_error_response(&e);
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    assert_eq!(body, "failed to connect to upstream\n");
+}
+
+#[test]
+fn interleaving_address_families() {
+    let addresses = [
+        "[2001:db8::1]:443",
+        "[2001:db8::2]:443",
+        "[2001:db8::3]:443",
+        "203.0.113.1:443",
+    ]
+    .map(|addr| addr.parse::<SocketAddr>().unwrap());
+    let [v6_1, v6_2, v6_3, v4] = addresses;
+    assert_eq!(interleave_families(&addresses), [v6_1, v4, v6_2, v6_3]);
+    assert_eq!(interleave_families(&[v4, v6_1]), [v4, v6_1]);
+    assert_eq!(interleave_families(&[]), []);
+}
+
+#[tokio::test]
+async fn happy_eyeballs() {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let listening = listener.local_addr().unwrap();
+    let closed = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+
+    // Falls back to the next address as soon as one fails.
+    let tcp_stream = connect_happy_eyeballs(&[closed, listening]).await.unwrap();
+    assert_eq!(tcp_stream.peer_addr().unwrap(), listening);
+
+    let e = connect_happy_eyeballs(&[closed, closed]).await.unwrap_err();
+    assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn hsts_only_over_https() {
+    let proxy = Proxy::new(Config {
+        hsts: Some(HeaderValue::from_static("max-age=60")),
+        ..test_config()
+    })
+    .unwrap();
+    let addr = "203.0.113.7:50000".parse().unwrap();
+    let health_check = || {
+        http::Request::builder()
+            .uri("/healthz")
+            .header(header::HOST, "example.com")
+            .body(hyper::Body::empty())
+            .unwrap()
+    };
+
+    let response = proxy
+        .for_peer(Some(addr), true)
+        .call(health_check())
+        .await
+        .unwrap();
+    assert_eq!(
+        response.headers()[header::STRICT_TRANSPORT_SECURITY],
+        "max-age=60"
+    );
+
+    let response = proxy
+        .for_peer(Some(addr), false)
+        .call(health_check())
+        .await
+        .unwrap();
+    assert!(!response
+        .headers()
+        .contains_key(header::STRICT_TRANSPORT_SECURITY));
+}
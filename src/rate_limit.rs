@@ -0,0 +1,143 @@
+//! Per-client token bucket rate limiting.
+
+use ::std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash as _, Hasher as _},
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+pub(crate) struct Config {
+    pub(crate) requests_per_second: f64,
+    pub(crate) burst: u32,
+}
+
+/// The number of independently locked maps buckets are spread between, to reduce contention.
+const SHARDS: usize = 16;
+
+/// How often each shard is scanned for buckets that can be forgotten.
+const EVICTION_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) struct RateLimiter {
+    config: Config,
+    shards: [Mutex<Shard>; SHARDS],
+}
+
+struct Shard {
+    buckets: HashMap<IpAddr, Bucket>,
+    last_eviction: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(config: Config) -> Self {
+        let now = Instant::now();
+        Self {
+            config,
+            shards: std::array::from_fn(|_| {
+                Mutex::new(Shard {
+                    buckets: HashMap::new(),
+                    last_eviction: now,
+                })
+            }),
+        }
+    }
+
+    /// Takes a token from the client's bucket. If there are none left, returns how long until
+    /// there will be one.
+    pub(crate) fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut hasher = DefaultHasher::new();
+        client.hash(&mut hasher);
+        // Truncation is fine, since only the low bits are used.
+        #[allow(clippy::cast_possible_truncation)]
+        let shard = &self.shards[hasher.finish() as usize % SHARDS];
+        let mut shard = shard.lock().unwrap();
+
+        if now.saturating_duration_since(shard.last_eviction) >= EVICTION_INTERVAL {
+            self.evict(&mut shard, now);
+        }
+
+        let capacity = self.capacity();
+        let bucket = shard.buckets.entry(client).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - bucket.tokens) / self.config.requests_per_second;
+            Err(Duration::from_secs_f64(wait))
+        }
+    }
+
+    /// Forgets buckets that have refilled completely, since they are the same as new ones.
+    fn evict(&self, shard: &mut Shard, now: Instant) {
+        shard.last_eviction = now;
+        shard
+            .buckets
+            .retain(|_, bucket| self.tokens(bucket, now) < self.capacity());
+    }
+
+    fn capacity(&self) -> f64 {
+        f64::from(self.config.burst.max(1))
+    }
+
+    /// The number of tokens in a bucket after refilling it up to the given time.
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.config.requests_per_second).min(self.capacity())
+    }
+}
+
+#[test]
+fn limiting() {
+    let limiter = RateLimiter::new(Config {
+        requests_per_second: 2.0,
+        burst: 3,
+    });
+    let client = IpAddr::from([203, 0, 113, 7]);
+    let other = IpAddr::from([203, 0, 113, 8]);
+    let start = Instant::now();
+
+    for _ in 0..3 {
+        limiter.check(client, start).unwrap();
+    }
+    assert_eq!(
+        limiter.check(client, start),
+        Err(Duration::from_millis(500))
+    );
+    limiter.check(other, start).unwrap();
+
+    let later = start + Duration::from_millis(500);
+    limiter.check(client, later).unwrap();
+    assert!(limiter.check(client, later).is_err());
+
+    let len = || {
+        limiter
+            .shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().buckets.len())
+            .sum::<usize>()
+    };
+    let evict = |now| {
+        for shard in &limiter.shards {
+            limiter.evict(&mut shard.lock().unwrap(), now);
+        }
+    };
+    // Buckets are forgotten once they have refilled.
+    evict(later);
+    assert_eq!(len(), 1);
+    evict(later + Duration::from_secs(1));
+    assert_eq!(len(), 1);
+    evict(later + Duration::from_millis(1500));
+    assert_eq!(len(), 0);
+}